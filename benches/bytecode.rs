@@ -0,0 +1,129 @@
+//! Compares the byte-encoded chunk against the `Vec<OpCode>` it replaced:
+//! the memory each takes, and the time to fetch and decode every instruction
+//! from each. Also times a call-heavy program end to end. Run with
+//! `cargo bench`.
+
+use std::hint::black_box;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+use rlox::chunk::{Chunk, OpCode};
+use rlox::compiler::Compiler;
+use rlox::memory::Heap;
+use rlox::object::Object;
+use rlox::value::Value;
+use rlox::vm::VM;
+
+const PROGRAM: &str = "
+fun fib(n) {
+    if (n < 2) return n;
+    return fib(n - 2) + fib(n - 1);
+}
+
+class Counter {
+    init() { this.count = 0; }
+    add(n) { this.count = this.count + n; return this; }
+}
+
+var counter = Counter();
+for (var i = 0; i < 1000; i = i + 1) {
+    counter.add(i);
+}
+var result = fib(20);
+";
+
+// The chunk and every function nested in its constant pool.
+fn chunks<'a>(chunk: &'a Chunk, heap: &'a Heap, out: &mut Vec<&'a Chunk>) {
+    out.push(chunk);
+    for value in &chunk.constants.values {
+        if let Value::Obj(obj) = value {
+            if let Object::Function(function) = heap.get(*obj) {
+                chunks(&function.chunk, heap, out);
+            }
+        }
+    }
+}
+
+// Stands in for the VM's dispatch: touch each instruction's operand.
+fn dispatch(op: OpCode) -> usize {
+    match op {
+        OpCode::Constant(index) => index as usize,
+        OpCode::GetLocal(slot) | OpCode::SetLocal(slot) => slot as usize,
+        OpCode::Jump(offset) | OpCode::JumpIfFalse(offset) | OpCode::Loop(offset) => {
+            offset as usize
+        }
+        _ => 1,
+    }
+}
+
+fn time(iterations: u32, mut f: impl FnMut() -> usize) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(f());
+    }
+    start.elapsed() / iterations
+}
+
+fn main() {
+    let mut vm = VM::new();
+    let script = Compiler::new(&mut vm, PROGRAM).compile().unwrap();
+    let mut encoded = Vec::new();
+    chunks(&script.chunk, vm.heap(), &mut encoded);
+
+    // The same instructions held the old way, one enum per instruction.
+    let enums: Vec<Vec<OpCode>> = encoded
+        .iter()
+        .map(|chunk| chunk.instructions().map(|(_, op)| op).collect())
+        .collect();
+
+    let instructions: usize = enums.iter().map(Vec::len).sum();
+    let enum_bytes: usize = enums
+        .iter()
+        .map(|ops| ops.len() * size_of::<OpCode>())
+        .sum();
+    let encoded_bytes: usize = encoded.iter().map(|chunk| chunk.code.len()).sum();
+
+    const DECODE_ITERATIONS: u32 = 10_000;
+    let enum_walk = time(DECODE_ITERATIONS, || {
+        let mut sum = 0;
+        for ops in &enums {
+            for &op in ops {
+                sum += dispatch(black_box(op));
+            }
+        }
+        sum
+    });
+    let encoded_walk = time(DECODE_ITERATIONS, || {
+        let mut sum = 0;
+        for chunk in &encoded {
+            let mut offset = 0;
+            while let Some((op, next)) = chunk.read_instruction(black_box(offset)) {
+                sum += dispatch(op);
+                offset = next;
+            }
+        }
+        sum
+    });
+
+    const RUN_ITERATIONS: u32 = 20;
+    let run = time(RUN_ITERATIONS, || {
+        VM::new().interpret(PROGRAM).unwrap();
+        0
+    });
+
+    println!("instructions:        {}", instructions);
+    println!(
+        "Vec<OpCode> bytes:   {} ({} per instruction)",
+        enum_bytes,
+        size_of::<OpCode>()
+    );
+    println!(
+        "encoded bytes:       {} ({:.2} per instruction)",
+        encoded_bytes,
+        encoded_bytes as f64 / instructions as f64
+    );
+    println!("cache lines saved:   {}", (enum_bytes - encoded_bytes) / 64);
+    println!("fetch Vec<OpCode>:   {:?} per pass", enum_walk);
+    println!("fetch encoded:       {:?} per pass", encoded_walk);
+    println!("interpret:           {:?} per run", run);
+}
@@ -1,36 +1,292 @@
+use std::io::{self, Write};
+
+use crate::memory::Heap;
 use crate::value::{Value, ValueArray};
 
+/// Largest operand a `...Long` instruction can carry.
+pub const MAX_LONG_OPERAND: usize = (1 << 24) - 1;
+
+// Each instruction is stored as its tag byte followed by its operands:
+// `u8` operands take one byte, jump offsets (`u16`) two, and the 24-bit
+// operands of the `...Long` forms three, all little-endian.
+macro_rules! opcodes {
+    ($($op:ident $(($($arg:ident: $ty:ty),+))? = $tag:literal),* $(,)?) => {
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum OpCode {
+            $($op $(($($ty),+))?),*
+        }
+
+        impl OpCode {
+            /// Number of bytes this instruction occupies in a chunk.
+            pub fn size(self) -> usize {
+                match self {
+                    $(OpCode::$op { .. } => 1 $($(+ <$ty as Operand>::SIZE)+)?,)*
+                }
+            }
+
+            fn encode(self, out: &mut Vec<u8>) {
+                match self {
+                    $(OpCode::$op $(($($arg),+))? => {
+                        out.push($tag);
+                        $($($arg.write(out);)+)?
+                    })*
+                }
+            }
+
+            fn decode(code: &[u8], offset: usize) -> Option<(OpCode, usize)> {
+                let mut at = offset + 1;
+                let op = match *code.get(offset)? {
+                    $($tag => OpCode::$op $(($(<$ty as Operand>::read(code, &mut at)?),+))?,)*
+                    _ => return None,
+                };
+                Some((op, at))
+            }
+        }
+    };
+}
+
+// Instructions that index the constant pool, a local slot or a global slot
+// have a `...Long` twin with a 24-bit operand, used once the index no longer
+// fits in a byte.
+opcodes! {
+    Constant(index: u8) = 0,
+    ConstantLong(index: u32) = 1,
+    Nil = 2,
+    True = 3,
+    False = 4,
+    Pop = 5,
+    GetLocal(slot: u8) = 6,
+    GetLocalLong(slot: u32) = 7,
+    GetGlobal(slot: u8) = 8,
+    GetGlobalLong(slot: u32) = 9,
+    DefineGlobal(slot: u8) = 10,
+    DefineGlobalLong(slot: u32) = 11,
+    SetLocal(slot: u8) = 12,
+    SetLocalLong(slot: u32) = 13,
+    SetGlobal(slot: u8) = 14,
+    SetGlobalLong(slot: u32) = 15,
+    GetUpvalue(slot: u8) = 16,
+    SetUpvalue(slot: u8) = 17,
+    GetProperty(name: u8) = 18,
+    GetPropertyLong(name: u32) = 19,
+    SetProperty(name: u8) = 20,
+    SetPropertyLong(name: u32) = 21,
+    GetSuper(name: u8) = 22,
+    GetSuperLong(name: u32) = 23,
+    Equal = 24,
+    Greater = 25,
+    Less = 26,
+    Add = 27,
+    Subtract = 28,
+    Multiply = 29,
+    Divide = 30,
+    Not = 31,
+    Negate = 32,
+    Print = 33,
+    Call(arg_count: u8) = 34,
+    Invoke(name: u8, arg_count: u8) = 35,
+    InvokeLong(name: u32, arg_count: u8) = 36,
+    SuperInvoke(name: u8, arg_count: u8) = 37,
+    SuperInvokeLong(name: u32, arg_count: u8) = 38,
+    Closure(index: u8) = 39,
+    ClosureLong(index: u32) = 40,
+    CloseUpvalue = 41,
+    Class(name: u8) = 42,
+    ClassLong(name: u32) = 43,
+    Inherit = 44,
+    Method(name: u8) = 45,
+    MethodLong(name: u32) = 46,
+    Jump(offset: u16) = 47,
+    JumpIfFalse(offset: u16) = 48,
+    Loop(offset: u16) = 49,
+    Return = 50,
+}
+
+trait Operand: Sized {
+    const SIZE: usize;
+
+    fn write(self, out: &mut Vec<u8>);
+    fn read(code: &[u8], at: &mut usize) -> Option<Self>;
+}
+
+fn read_bytes<const N: usize>(code: &[u8], at: &mut usize) -> Option<[u8; N]> {
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(code.get(*at..*at + N)?);
+    *at += N;
+    Some(bytes)
+}
+
+impl Operand for u8 {
+    const SIZE: usize = 1;
+
+    fn write(self, out: &mut Vec<u8>) {
+        out.push(self);
+    }
+
+    fn read(code: &[u8], at: &mut usize) -> Option<Self> {
+        read_bytes::<1>(code, at).map(|[byte]| byte)
+    }
+}
+
+impl Operand for u16 {
+    const SIZE: usize = 2;
+
+    fn write(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn read(code: &[u8], at: &mut usize) -> Option<Self> {
+        read_bytes(code, at).map(u16::from_le_bytes)
+    }
+}
+
+// Only the low 24 bits are stored.
+impl Operand for u32 {
+    const SIZE: usize = 3;
+
+    fn write(self, out: &mut Vec<u8>) {
+        debug_assert!(self as usize <= MAX_LONG_OPERAND);
+        out.extend_from_slice(&self.to_le_bytes()[..3]);
+    }
+
+    fn read(code: &[u8], at: &mut usize) -> Option<Self> {
+        read_bytes(code, at).map(|[a, b, c]| u32::from_le_bytes([a, b, c, 0]))
+    }
+}
+
+/// Where in the source an instruction came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Source positions for a chunk's bytes. A run is recorded only where the
+/// position changes, as three LEB128 varints: the byte offset delta from the
+/// previous run, the zigzag-encoded line delta, and the column.
+///
+/// Every 32 runs the decoder's state is saved, so a lookup binary searches
+/// the checkpoints and decodes at most that many runs.
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    runs: Vec<u8>,
+    last: Option<(usize, Position)>,
+    count: usize,
+    checkpoints: Vec<Checkpoint>,
+}
+
+const CHECKPOINT_EVERY: usize = 32;
+
+// A run's offset and position, and where the run after it starts in `runs`.
 #[derive(Debug, Clone, Copy)]
-pub enum OpCode {
-    Constant(u8),
-    Nil,
-    True,
-    False,
-    Pop,
-    GetLocal(u8),
-    GetGlobal(u8),
-    DefineGlobal(u8),
-    SetLocal(u8),
-    SetGlobal(u8),
-    Equal,
-    Greater,
-    Less,
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Not,
-    Negate,
-    Print,
-    Jump(u16),
-    JumpIfFalse(u16),
-    Return,
+struct Checkpoint {
+    offset: usize,
+    position: Position,
+    next: usize,
+}
+
+impl LineTable {
+    pub(crate) fn push(&mut self, offset: usize, position: Position) {
+        let (last_offset, last_line) = match self.last {
+            Some((_, last)) if last == position => return,
+            Some((offset, last)) => (offset, last.line),
+            None => (0, 0),
+        };
+        let line_delta = position.line as i64 - last_line as i64;
+        write_varint(&mut self.runs, (offset - last_offset) as u64);
+        write_varint(
+            &mut self.runs,
+            ((line_delta << 1) ^ (line_delta >> 63)) as u64,
+        );
+        write_varint(&mut self.runs, position.column as u64);
+        self.last = Some((offset, position));
+
+        self.count += 1;
+        if self.count.is_multiple_of(CHECKPOINT_EVERY) {
+            self.checkpoints.push(Checkpoint {
+                offset,
+                position,
+                next: self.runs.len(),
+            });
+        }
+    }
+
+    /// Position of the byte at `offset`.
+    pub fn get(&self, offset: usize) -> Option<Position> {
+        let before = self.checkpoints.partition_point(|c| c.offset <= offset);
+        let (checkpoint, runs) = match before.checked_sub(1) {
+            Some(i) => {
+                let c = self.checkpoints[i];
+                (
+                    Some(c.position),
+                    self.runs_from(c.next, c.offset, c.position.line),
+                )
+            }
+            None => (None, self.runs_from(0, 0, 0)),
+        };
+        runs.take_while(|&(start, _)| start <= offset)
+            .last()
+            .map(|(_, position)| position)
+            .or(checkpoint)
+    }
+
+    /// Each run's starting offset and position, in order.
+    pub fn runs(&self) -> impl Iterator<Item = (usize, Position)> + '_ {
+        self.runs_from(0, 0, 0)
+    }
+
+    // Decodes the runs starting at byte `at`, where the previous run began at
+    // `offset` on `line`.
+    fn runs_from(
+        &self,
+        mut at: usize,
+        mut offset: usize,
+        mut line: usize,
+    ) -> impl Iterator<Item = (usize, Position)> + '_ {
+        std::iter::from_fn(move || {
+            if at >= self.runs.len() {
+                return None;
+            }
+            offset += read_varint(&self.runs, &mut at) as usize;
+            let zigzag = read_varint(&self.runs, &mut at) as i64;
+            line = (line as i64 + ((zigzag >> 1) ^ -(zigzag & 1))) as usize;
+            let column = read_varint(&self.runs, &mut at) as usize;
+            Some((offset, Position { line, column }))
+        })
+    }
+
+    /// Bytes used by the encoded table and its checkpoints.
+    pub fn size(&self) -> usize {
+        self.runs.capacity() + self.checkpoints.capacity() * std::mem::size_of::<Checkpoint>()
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], at: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*at];
+        *at += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return value;
+        }
+        shift += 7;
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Chunk {
-    pub code: Vec<OpCode>,
-    pub lines: Vec<i32>,
+    pub code: Vec<u8>,
+    pub lines: LineTable,
     pub constants: ValueArray,
 }
 
@@ -38,14 +294,31 @@ impl Chunk {
     pub fn new() -> Self {
         Chunk {
             code: Vec::new(),
-            lines: Vec::new(),
+            lines: LineTable::default(),
             constants: ValueArray::new(),
         }
     }
 
-    pub fn write_chunk(&mut self, byte: OpCode, line: i32) {
-        self.code.push(byte);
-        self.lines.push(line);
+    pub fn write_chunk(&mut self, op: OpCode, position: Position) {
+        self.lines.push(self.code.len(), position);
+        op.encode(&mut self.code);
+    }
+
+    /// Overwrites the instruction at `offset` with one of the same size.
+    pub fn patch(&mut self, offset: usize, op: OpCode) {
+        let mut bytes = Vec::with_capacity(op.size());
+        op.encode(&mut bytes);
+        self.code[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+
+    /// Source line of the instruction byte at `offset`.
+    pub fn line_at(&self, offset: usize) -> usize {
+        self.position_at(offset).line
+    }
+
+    /// Source line and column of the instruction byte at `offset`.
+    pub fn position_at(&self, offset: usize) -> Position {
+        self.lines.get(offset).expect("offset outside the chunk")
     }
 
     pub fn add_constant(&mut self, value: Value) -> usize {
@@ -53,27 +326,203 @@ impl Chunk {
         self.constants.len() - 1
     }
 
-    pub fn disassemble(&self, name: &str) {
-        println!("=== {} ===", name);
+    /// Decodes the instruction starting at `offset`, returning it with the
+    /// offset of the next one. `None` if the bytes there aren't a whole
+    /// instruction.
+    pub fn read_instruction(&self, offset: usize) -> Option<(OpCode, usize)> {
+        OpCode::decode(&self.code, offset)
+    }
+
+    /// Every instruction in the chunk, with its byte offset.
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions {
+            chunk: self,
+            offset: 0,
+        }
+    }
+
+    pub fn disassemble(&self, name: &str, heap: &Heap, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "=== {} ===", name)?;
 
-        for offset in 0..self.code.len() {
-            disassemble_instruction(self, offset);
+        // Walk the line table alongside the code rather than searching it
+        // for every instruction.
+        let mut runs = self.lines.runs().peekable();
+        let mut line = None;
+        for (offset, op) in self.instructions() {
+            let previous = line;
+            while let Some((_, position)) = runs.next_if(|&(start, _)| start <= offset) {
+                line = Some(position.line);
+            }
+            print_instruction(self, offset, op, line.unwrap(), previous, heap, out)?;
         }
+        Ok(())
+    }
+}
+
+pub struct Instructions<'a> {
+    chunk: &'a Chunk,
+    offset: usize,
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = (usize, OpCode);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        let (op, next) = self.chunk.read_instruction(offset)?;
+        self.offset = next;
+        Some((offset, op))
     }
 }
 
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize) {
-    print!("{:04} ", offset);
+/// Writes the instruction at `offset` and returns the offset of the next one.
+pub fn disassemble_instruction(
+    chunk: &Chunk,
+    offset: usize,
+    heap: &Heap,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    let (op, next) = chunk
+        .read_instruction(offset)
+        .expect("offset is not an instruction");
+    let previous = offset.checked_sub(1).map(|offset| chunk.line_at(offset));
+    print_instruction(
+        chunk,
+        offset,
+        op,
+        chunk.line_at(offset),
+        previous,
+        heap,
+        out,
+    )?;
+    Ok(next)
+}
+
+fn print_instruction(
+    chunk: &Chunk,
+    offset: usize,
+    op: OpCode,
+    line: usize,
+    previous_line: Option<usize>,
+    heap: &Heap,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    write!(out, "{:04} ", offset)?;
 
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        print!("   | ");
+    if previous_line == Some(line) {
+        write!(out, "   | ")?;
     } else {
-        print!("{:4} ", chunk.lines[offset]);
+        write!(out, "{:4} ", line)?;
     }
-    match chunk.code[offset] {
+    let next = offset + op.size();
+    match op {
         OpCode::Constant(off) => {
-            println!("Constant {}", chunk.constants[off]);
+            writeln!(out, "Constant {}", chunk.constants[off].display(heap))
+        }
+        OpCode::ConstantLong(off) => writeln!(
+            out,
+            "ConstantLong {}",
+            chunk.constants.values[off as usize].display(heap)
+        ),
+        OpCode::Jump(jump) | OpCode::JumpIfFalse(jump) => {
+            writeln!(out, "{:?} -> {}", op, next + jump as usize)
+        }
+        OpCode::Loop(jump) => writeln!(out, "{:?} -> {}", op, next - jump as usize),
+        _ => writeln!(out, "{:?}", op),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(line: usize, column: usize) -> Position {
+        Position { line, column }
+    }
+
+    #[test]
+    fn instructions_round_trip() {
+        let ops = [
+            OpCode::Constant(7),
+            OpCode::ConstantLong(0x12_3456),
+            OpCode::Nil,
+            OpCode::InvokeLong(MAX_LONG_OPERAND as u32, 3),
+            OpCode::Jump(0x0102),
+            OpCode::Return,
+        ];
+        let mut chunk = Chunk::new();
+        for (line, &op) in ops.iter().enumerate() {
+            chunk.write_chunk(op, at(line, 1));
+        }
+
+        assert_eq!(chunk.code.len(), 2 + 4 + 1 + 5 + 3 + 1);
+        let decoded: Vec<_> = chunk.instructions().collect();
+        let offsets: Vec<_> = decoded.iter().map(|&(offset, _)| offset).collect();
+        let decoded: Vec<_> = decoded.into_iter().map(|(_, op)| op).collect();
+        assert_eq!(decoded, ops);
+        assert_eq!(offsets, [0, 2, 6, 7, 12, 15]);
+    }
+
+    #[test]
+    fn patch_rewrites_operands_in_place() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::JumpIfFalse(0), at(1, 1));
+        chunk.write_chunk(OpCode::Pop, at(1, 1));
+        chunk.patch(0, OpCode::JumpIfFalse(1));
+
+        assert_eq!(chunk.read_instruction(0), Some((OpCode::JumpIfFalse(1), 3)));
+        assert_eq!(chunk.read_instruction(3), Some((OpCode::Pop, 4)));
+    }
+
+    #[test]
+    fn truncated_instruction_does_not_decode() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::ConstantLong(300), at(1, 1));
+        chunk.code.pop();
+
+        assert_eq!(chunk.read_instruction(0), None);
+        assert_eq!(chunk.instructions().count(), 0);
+    }
+
+    #[test]
+    fn line_table_records_runs() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Constant(0), at(1, 9));
+        chunk.write_chunk(OpCode::Nil, at(1, 9));
+        chunk.write_chunk(OpCode::Add, at(1, 7));
+        chunk.write_chunk(OpCode::ConstantLong(0), at(300, 2));
+        chunk.write_chunk(OpCode::Return, at(2, 1));
+
+        let runs: Vec<_> = chunk.lines.runs().collect();
+        assert_eq!(
+            runs,
+            [(0, at(1, 9)), (3, at(1, 7)), (4, at(300, 2)), (8, at(2, 1))]
+        );
+        assert_eq!(chunk.position_at(1), at(1, 9));
+        assert_eq!(chunk.position_at(2), at(1, 9));
+        assert_eq!(chunk.position_at(3), at(1, 7));
+        assert_eq!(chunk.line_at(7), 300);
+        assert_eq!(chunk.line_at(8), 2);
+    }
+
+    #[test]
+    fn line_lookups_agree_across_checkpoints() {
+        let mut chunk = Chunk::new();
+        for i in 0..1000 {
+            // A new line every few instructions, sometimes going backwards.
+            let line = if i % 7 == 0 { i / 2 } else { i / 3 + 1 };
+            chunk.write_chunk(OpCode::Constant(0), at(line, i % 5));
+        }
+
+        let runs: Vec<_> = chunk.lines.runs().collect();
+        assert!(runs.len() > CHECKPOINT_EVERY * 4);
+        for offset in 0..chunk.code.len() {
+            let expected = runs
+                .iter()
+                .take_while(|&&(start, _)| start <= offset)
+                .last()
+                .map(|&(_, position)| position);
+            assert_eq!(chunk.lines.get(offset), expected, "offset {}", offset);
         }
-        _ => println!("{:?}", &chunk.code[offset]),
     }
 }
@@ -1,2 +1,45 @@
-pub const DEBUG_TRACE_EXECUTION: bool = false;
-pub const DEBUG_PRINT_CODE: bool = true;
+/// Debugging switches for a [`VM`](crate::vm::VM), all off by default.
+///
+/// ```
+/// use rlox::common::VmOptions;
+/// use rlox::vm::VM;
+///
+/// let vm = VM::with_options(VmOptions::new().print_code(true).stress_gc(true));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VmOptions {
+    pub(crate) trace_execution: bool,
+    pub(crate) print_code: bool,
+    pub(crate) dump_tokens: bool,
+    pub(crate) stress_gc: bool,
+}
+
+impl VmOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Disassemble each instruction to the trace output before running it.
+    pub fn trace_execution(mut self, on: bool) -> Self {
+        self.trace_execution = on;
+        self
+    }
+
+    /// Disassemble each function to the trace output once it compiles.
+    pub fn print_code(mut self, on: bool) -> Self {
+        self.print_code = on;
+        self
+    }
+
+    /// Write every token the compiler scans to the trace output.
+    pub fn dump_tokens(mut self, on: bool) -> Self {
+        self.dump_tokens = on;
+        self
+    }
+
+    /// Run a full collection before every allocation.
+    pub fn stress_gc(mut self, on: bool) -> Self {
+        self.stress_gc = on;
+        self
+    }
+}
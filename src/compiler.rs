@@ -1,15 +1,21 @@
-use crate::chunk::{Chunk, OpCode};
-use crate::common::DEBUG_PRINT_CODE;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::chunk::{Chunk, OpCode, Position, MAX_LONG_OPERAND};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::memory::ObjRef;
+use crate::object::{Function, Object, UpvalueIndex};
 use crate::scanner::{Scanner, Token, TokenType};
 use crate::value::Value;
 use crate::vm::VM;
 
 pub struct Compiler<'src> {
     vm: &'src mut VM,
+    source: &'src str,
     parser: Parser<'src>,
     scanner: Scanner<'src>,
     current: Compiler2<'src>,
-    compiling_chunk: &'src mut Chunk,
+    classes: Vec<ClassCompiler>,
     parse_rule_table: ParseRuleTable<'src>,
 }
 
@@ -18,19 +24,48 @@ struct Parser<'src> {
     previous: Option<Token<'src>>,
     had_error: bool,
     panic_mode: bool,
+    diagnostics: Vec<Diagnostic>,
 }
 
 // stackframe
 struct Compiler2<'src> {
+    enclosing: Option<Box<Compiler2<'src>>>,
+    function: Function,
+    typ: FunctionType,
+
     locals: Vec<Local<'src>>,
     //local_count: i32,
     scope_depth: i32,
+    loops: Vec<Loop>,
+    // Constant index of each identifier name already in the chunk.
+    identifiers: HashMap<ObjRef, u32>,
+}
+
+// innermost enclosing loop, for break/continue
+struct Loop {
+    start: usize,
+    scope_depth: i32,
+    break_jumps: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+// enclosing class declarations, innermost last
+struct ClassCompiler {
+    has_superclass: bool,
 }
 
 #[derive(Debug, Clone)]
 struct Local<'src> {
     name: Token<'src>,
     depth: i32,
+    is_captured: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -127,12 +162,12 @@ impl<'src> ParseRuleTable<'src> {
 
         use TokenType::*;
         rules! {
-            { LeftParen,    { Some(Compiler::grouping), None, Precedence::None } },
+            { LeftParen,    { Some(Compiler::grouping), Some(Compiler::call), Precedence::Call } },
             { RightParen,   { None, None, Precedence::None } },
             { LeftBrace,    { None, None, Precedence::None } },
             { RightBrace,   { None, None, Precedence::None } },
             { Comma,        { None, None, Precedence::None } },
-            { Dot,          { None, None, Precedence::None } },
+            { Dot,          { None, Some(Compiler::dot), Precedence::Call } },
             { Minus,        { Some(Compiler::unary), Some(Compiler::binary), Precedence::Term } },
             { Plus,         { None, Some(Compiler::binary), Precedence::Term } },
             { Semicolon,    { None, None, Precedence::None } },
@@ -150,7 +185,9 @@ impl<'src> ParseRuleTable<'src> {
             { String,       { Some(Compiler::string), None, Precedence::None } },
             { Number,       { Some(Compiler::number), None, Precedence::None } },
             { And,          { None, Some(Compiler::and_), Precedence::And } },
+            { Break,        { None, None, Precedence::None } },
             { Class,        { None, None, Precedence::None } },
+            { Continue,     { None, None, Precedence::None } },
             { Else,         { None, None, Precedence::None } },
             { False,        { Some(Compiler::literal), None, Precedence::None } },
             { For,          { None, None, Precedence::None } },
@@ -160,8 +197,8 @@ impl<'src> ParseRuleTable<'src> {
             { Or,           { None, Some(Compiler::or_), Precedence::Or } },
             { Print,        { None, None, Precedence::None } },
             { Return,       { None, None, Precedence::None } },
-            { Super,        { None, None, Precedence::None } },
-            { This,         { None, None, Precedence::None } },
+            { Super,        { Some(Compiler::super_), None, Precedence::None } },
+            { This,         { Some(Compiler::this_), None, Precedence::None } },
             { True,         { Some(Compiler::literal), None, Precedence::None } },
             { Var,          { None, None, Precedence::None } },
             { While,        { None, None, Precedence::None } },
@@ -174,38 +211,51 @@ impl<'src> ParseRuleTable<'src> {
 }
 
 impl<'src> Compiler<'src> {
-    pub fn new(vm: &'src mut VM, source: &'src str, chunk: &'src mut Chunk) -> Self {
+    pub fn new(vm: &'src mut VM, source: &'src str) -> Self {
         Compiler {
             vm,
+            source,
             parser: Parser::new(),
             scanner: Scanner::new(source),
-            current: Compiler2::new(),
-            compiling_chunk: chunk,
+            current: Compiler2::new(FunctionType::Script, None),
+            classes: Vec::new(),
             parse_rule_table: ParseRuleTable::new(),
         }
     }
 
-    pub fn compile(&mut self) -> anyhow::Result<()> {
+    /// Compiles the whole source as a script, or returns every diagnostic
+    /// reported along the way.
+    pub fn compile(&mut self) -> Result<Function, Vec<Diagnostic>> {
         self.advance();
 
-        while !self.matches(TokenType::Eof) {
+        while !self.check(TokenType::Eof) {
             self.declaration();
         }
 
-        self.end_compiler();
+        let function = self.end_compiler();
 
         if self.parser.had_error {
-            anyhow::bail!("parse error");
+            return Err(std::mem::take(&mut self.parser.diagnostics));
         }
-        Ok(())
+        Ok(function)
     }
 
-    fn end_compiler(&mut self) {
+    fn end_compiler(&mut self) -> Function {
         self.emit_return();
-        if DEBUG_PRINT_CODE && !self.parser.had_error {
-            let chunk = self.current_chunk();
-            chunk.disassemble("code");
+        if self.vm.options().print_code && !self.parser.had_error {
+            let function = &self.current.function;
+            self.vm.trace_chunk(&function.chunk, &function.to_string());
         }
+
+        let enclosing = self.current.enclosing.take();
+        let compiler = match enclosing {
+            Some(enclosing) => std::mem::replace(&mut self.current, *enclosing),
+            None => std::mem::replace(
+                &mut self.current,
+                Compiler2::new(FunctionType::Script, None),
+            ),
+        };
+        compiler.function
     }
 
     fn begin_scope(&mut self) {
@@ -221,7 +271,11 @@ impl<'src> Compiler<'src> {
                 break;
             }
 
-            self.emit_byte(OpCode::Pop);
+            if self.current.locals[len - 1].is_captured {
+                self.emit_byte(OpCode::CloseUpvalue);
+            } else {
+                self.emit_byte(OpCode::Pop);
+            }
             self.current.locals.pop();
         }
     }
@@ -229,7 +283,13 @@ impl<'src> Compiler<'src> {
     fn advance(&mut self) {
         self.parser.previous = self.parser.current.take();
         loop {
-            let token = dbg!(self.scanner.scan_token());
+            let token = self.scanner.scan_token();
+            if self.vm.options().dump_tokens {
+                self.vm.trace_line(format_args!(
+                    "{:4}:{:<3} {:?} '{}'",
+                    token.line, token.column, token.typ, token.name
+                ));
+            }
             let typ = token.typ;
             let message = token.name;
             self.parser.current = Some(token);
@@ -254,6 +314,114 @@ impl<'src> Compiler<'src> {
         self.consume(TokenType::RightBrace, "Expect '}' after block.");
     }
 
+    fn function(&mut self, typ: FunctionType) {
+        let name = self.parser.previous.clone().unwrap().name.to_owned();
+        let enclosing = std::mem::replace(&mut self.current, Compiler2::new(typ, Some(name)));
+        self.current.enclosing = Some(Box::new(enclosing));
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.current.function.arity += 1;
+                if self.current.function.arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let function = self.end_compiler();
+        let function = self.vm.alloc(Object::Function(function));
+        let constant = self.make_constant(Value::Obj(function));
+        self.emit_byte(indexed(constant, OpCode::Closure, OpCode::ClosureLong));
+    }
+
+    fn method(&mut self) {
+        if !self.check(TokenType::Identifier) {
+            // Skip the stray token; the class body loop retries from the next one.
+            self.error_at_current("Expect method name.");
+            self.advance();
+            return;
+        }
+        self.advance();
+        let name = self.parser.previous.clone().unwrap();
+        let constant = self.identifier_constant(name.clone());
+
+        let typ = if name.name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(typ);
+        self.emit_byte(indexed(constant, OpCode::Method, OpCode::MethodLong));
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.parser.previous.clone().unwrap();
+        let name_constant = self.identifier_constant(class_name.clone());
+        self.declare_variable();
+        let global = if self.current.scope_depth > 0 {
+            0
+        } else {
+            self.global_slot(class_name.clone())
+        };
+
+        self.emit_byte(indexed(name_constant, OpCode::Class, OpCode::ClassLong));
+        self.define_variable(global);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.matches(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            let superclass_name = self.parser.previous.clone().unwrap();
+            if self.identifiers_equal(&class_name, &superclass_name) {
+                self.error("A class can't inherit from itself.");
+            }
+
+            self.begin_scope();
+            self.add_local(synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(class_name.clone(), false);
+            self.emit_byte(OpCode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop);
+
+        if self.classes.last().unwrap().has_superclass {
+            self.end_scope();
+        }
+        self.classes.pop();
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
     fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
 
@@ -264,7 +432,7 @@ impl<'src> Compiler<'src> {
         }
         self.consume(
             TokenType::Semicolon,
-            "Expect ';' after variable declaration",
+            "Expect ';' after variable declaration.",
         );
 
         self.define_variable(global);
@@ -295,20 +463,178 @@ impl<'src> Compiler<'src> {
         self.patch_jump(else_jump, OpCode::Jump(0));
     }
 
+    fn return_statement(&mut self) {
+        if self.current.typ == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.matches(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current.typ == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return);
+        }
+    }
+
     fn print_statement(&mut self) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
         self.emit_byte(OpCode::Print);
     }
 
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0));
+        self.emit_byte(OpCode::Pop);
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump, OpCode::JumpIfFalse(0));
+        self.emit_byte(OpCode::Pop);
+        self.end_loop();
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.matches(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.matches(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.matches(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse(0)));
+            self.emit_byte(OpCode::Pop); // Condition.
+        }
+
+        if !self.matches(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump(0));
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit_byte(OpCode::Pop);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump, OpCode::Jump(0));
+        }
+
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump, OpCode::JumpIfFalse(0));
+            self.emit_byte(OpCode::Pop); // Condition.
+        }
+        self.end_loop();
+
+        self.end_scope();
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        let scope_depth = self.current.scope_depth;
+        self.current.loops.push(Loop {
+            start,
+            scope_depth,
+            break_jumps: Vec::new(),
+        });
+    }
+
+    fn end_loop(&mut self) {
+        let lp = self.current.loops.pop().expect("end_loop outside loop");
+        for jump in lp.break_jumps {
+            self.patch_jump(jump, OpCode::Jump(0));
+        }
+    }
+
+    fn break_statement(&mut self) {
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+        let scope_depth = match self.current.loops.last() {
+            Some(lp) => lp.scope_depth,
+            None => {
+                self.error("Can't use 'break' outside of a loop.");
+                return;
+            }
+        };
+
+        self.pop_locals_deeper_than(scope_depth);
+        let jump = self.emit_jump(OpCode::Jump(0));
+        self.current
+            .loops
+            .last_mut()
+            .unwrap()
+            .break_jumps
+            .push(jump);
+    }
+
+    fn continue_statement(&mut self) {
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+        let (start, scope_depth) = match self.current.loops.last() {
+            Some(lp) => (lp.start, lp.scope_depth),
+            None => {
+                self.error("Can't use 'continue' outside of a loop.");
+                return;
+            }
+        };
+
+        self.pop_locals_deeper_than(scope_depth);
+        self.emit_loop(start);
+    }
+
+    // Discard the locals of the scopes a jump leaves, without forgetting them
+    // at compile time: the code after the jump still sees them.
+    fn pop_locals_deeper_than(&mut self, depth: i32) {
+        let ops: Vec<OpCode> = self
+            .current
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .map(|local| {
+                if local.is_captured {
+                    OpCode::CloseUpvalue
+                } else {
+                    OpCode::Pop
+                }
+            })
+            .collect();
+        self.emit_bytes(&ops);
+    }
+
+    // Skips tokens until something that looks like a statement boundary, so
+    // the errors that follow one mistake aren't reported.
     fn synchronize(&mut self) {
         self.parser.panic_mode = false;
 
-        while self.parser.current.clone().unwrap().typ != TokenType::Eof {
-            if self.parser.previous.clone().unwrap().typ == TokenType::Semicolon {
+        while !self.check(TokenType::Eof) {
+            if let Some(TokenType::Semicolon) = self.parser.previous.as_ref().map(|t| t.typ) {
                 return;
             }
-            match self.parser.current.clone().unwrap().typ {
+            match self.parser.current.as_ref().unwrap().typ {
+                // Leave a closing brace for the enclosing block to consume.
+                TokenType::RightBrace if self.current.scope_depth > 0 => {
+                    return;
+                }
                 TokenType::Class
                 | TokenType::Fun
                 | TokenType::Var
@@ -316,7 +642,9 @@ impl<'src> Compiler<'src> {
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
-                | TokenType::Return => {
+                | TokenType::Return
+                | TokenType::Break
+                | TokenType::Continue => {
                     return;
                 }
                 _ => {
@@ -329,7 +657,11 @@ impl<'src> Compiler<'src> {
     }
 
     fn declaration(&mut self) {
-        if self.matches(TokenType::Var) {
+        if self.matches(TokenType::Class) {
+            self.class_declaration();
+        } else if self.matches(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.matches(TokenType::Var) {
             self.var_declaration();
         } else {
             self.statement();
@@ -343,8 +675,18 @@ impl<'src> Compiler<'src> {
     fn statement(&mut self) {
         if self.matches(TokenType::Print) {
             self.print_statement();
+        } else if self.matches(TokenType::Return) {
+            self.return_statement();
         } else if self.matches(TokenType::If) {
             self.if_statement();
+        } else if self.matches(TokenType::While) {
+            self.while_statement();
+        } else if self.matches(TokenType::For) {
+            self.for_statement();
+        } else if self.matches(TokenType::Break) {
+            self.break_statement();
+        } else if self.matches(TokenType::Continue) {
+            self.continue_statement();
         } else if self.matches(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -364,8 +706,8 @@ impl<'src> Compiler<'src> {
         let tok = self.parser.previous.clone().expect("string");
         let len = tok.name.len();
         //self.emit_constant(Value::new_string(&tok.name[1..len - 1]));
-        let string = self.vm.new_string(&tok.name[1..len - 1]);
-        self.emit_constant(string);
+        let string = self.vm.intern(&tok.name[1..len - 1]);
+        self.emit_constant(Value::Obj(string));
     }
 
     fn variable(&mut self, can_assign: bool) {
@@ -374,10 +716,18 @@ impl<'src> Compiler<'src> {
 
     fn named_variable(&mut self, name: Token, can_assign: bool) {
         let (get_op, set_op) = if let Some(arg) = self.resolve_local(&name) {
-            (OpCode::GetLocal(arg), OpCode::SetLocal(arg))
+            (
+                indexed(arg, OpCode::GetLocal, OpCode::GetLocalLong),
+                indexed(arg, OpCode::SetLocal, OpCode::SetLocalLong),
+            )
+        } else if let Some(arg) = self.resolve_upvalue(&name) {
+            (OpCode::GetUpvalue(arg), OpCode::SetUpvalue(arg))
         } else {
-            let arg = self.identifier_constant(name);
-            (OpCode::GetGlobal(arg), OpCode::SetGlobal(arg))
+            let arg = self.global_slot(name);
+            (
+                indexed(arg, OpCode::GetGlobal, OpCode::GetGlobalLong),
+                indexed(arg, OpCode::SetGlobal, OpCode::SetGlobalLong),
+            )
         };
 
         if can_assign && self.matches(TokenType::Equal) {
@@ -405,8 +755,8 @@ impl<'src> Compiler<'src> {
 
         // Emit the operator instruction.
         match tok.typ {
-            TokenType::Bang => self.emit_byte(OpCode::Not),
-            TokenType::Minus => self.emit_byte(OpCode::Negate),
+            TokenType::Bang => self.emit_bytes_at(&tok, &[OpCode::Not]),
+            TokenType::Minus => self.emit_bytes_at(&tok, &[OpCode::Negate]),
             _ => unreachable!(),
         }
     }
@@ -416,21 +766,102 @@ impl<'src> Compiler<'src> {
         let rule = self.get_rule(tok.typ);
         self.parse_precedence(rule.precedence.next());
 
-        match tok.typ {
-            TokenType::BangEqual => self.emit_bytes(&[OpCode::Equal, OpCode::Not]),
-            TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
-            TokenType::Greater => self.emit_byte(OpCode::Greater),
-            TokenType::GreaterEqual => self.emit_bytes(&[OpCode::Less, OpCode::Not]),
-            TokenType::Less => self.emit_byte(OpCode::Less),
-            TokenType::LessEqual => self.emit_bytes(&[OpCode::Greater, OpCode::Not]),
-            TokenType::Plus => self.emit_byte(OpCode::Add),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract),
-            TokenType::Star => self.emit_byte(OpCode::Multiply),
-            TokenType::Slash => self.emit_byte(OpCode::Divide),
+        let ops: &[OpCode] = match tok.typ {
+            TokenType::BangEqual => &[OpCode::Equal, OpCode::Not],
+            TokenType::EqualEqual => &[OpCode::Equal],
+            TokenType::Greater => &[OpCode::Greater],
+            TokenType::GreaterEqual => &[OpCode::Less, OpCode::Not],
+            TokenType::Less => &[OpCode::Less],
+            TokenType::LessEqual => &[OpCode::Greater, OpCode::Not],
+            TokenType::Plus => &[OpCode::Add],
+            TokenType::Minus => &[OpCode::Subtract],
+            TokenType::Star => &[OpCode::Multiply],
+            TokenType::Slash => &[OpCode::Divide],
             _ => unreachable!(),
+        };
+        self.emit_bytes_at(&tok, ops);
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_byte(OpCode::Call(arg_count));
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count.min(255) as u8
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.parser.previous.clone().unwrap());
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(indexed(name, OpCode::SetProperty, OpCode::SetPropertyLong));
+        } else if self.matches(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_byte(indexed(
+                name,
+                |name| OpCode::Invoke(name, arg_count),
+                |name| OpCode::InvokeLong(name, arg_count),
+            ));
+        } else {
+            self.emit_byte(indexed(name, OpCode::GetProperty, OpCode::GetPropertyLong));
         }
     }
 
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => {}
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(self.parser.previous.clone().unwrap());
+
+        self.named_variable(synthetic_token("this"), false);
+        if self.matches(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(synthetic_token("super"), false);
+            self.emit_byte(indexed(
+                name,
+                |name| OpCode::SuperInvoke(name, arg_count),
+                |name| OpCode::SuperInvokeLong(name, arg_count),
+            ));
+        } else {
+            self.named_variable(synthetic_token("super"), false);
+            self.emit_byte(indexed(name, OpCode::GetSuper, OpCode::GetSuperLong));
+        }
+    }
+
+    fn this_(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
+    }
+
     fn literal(&mut self, _can_assign: bool) {
         match self.parser.previous.clone().unwrap().typ {
             TokenType::False => self.emit_byte(OpCode::False),
@@ -444,7 +875,6 @@ impl<'src> Compiler<'src> {
         self.advance();
 
         let rule = self.get_rule(self.parser.previous.clone().unwrap().typ);
-        dbg!(&rule);
         let can_assign = precedence <= Precedence::Assignment;
         if let Some(prefix_rule) = rule.prefix {
             prefix_rule(self, can_assign);
@@ -463,14 +893,14 @@ impl<'src> Compiler<'src> {
             if let Some(infix_rule) = rule.infix {
                 infix_rule(self, can_assign);
             }
+        }
 
-            if can_assign && self.matches(TokenType::Equal) {
-                self.error("Invalid assignment target.");
-            }
+        if can_assign && self.matches(TokenType::Equal) {
+            self.error("Invalid assignment target.");
         }
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
+    fn parse_variable(&mut self, error_message: &str) -> u32 {
         self.consume(TokenType::Identifier, error_message);
 
         self.declare_variable();
@@ -479,40 +909,72 @@ impl<'src> Compiler<'src> {
         }
 
         let p = self.parser.previous.clone().unwrap();
-        self.identifier_constant(p)
+        self.global_slot(p)
     }
 
     fn mark_initialized(&mut self) {
+        if self.current.scope_depth == 0 {
+            return;
+        }
         let len = self.current.locals.len();
         self.current.locals[len - 1].depth = self.current.scope_depth;
     }
 
-    fn identifier_constant(&mut self, name: Token) -> u8 {
-        let v = self.vm.new_string(name.name);
-        self.make_constant(v)
+    fn identifier_constant(&mut self, name: Token) -> u32 {
+        let name = self.vm.intern(name.name);
+
+        // Interned names are the same object, so one constant serves every use.
+        if let Some(&index) = self.current.identifiers.get(&name) {
+            return index;
+        }
+        let index = self.make_constant(Value::Obj(name));
+        self.current.identifiers.insert(name, index);
+        index
+    }
+
+    fn global_slot(&mut self, name: Token) -> u32 {
+        let name = self.vm.intern(name.name);
+        let slot = self.vm.global_slot(name);
+        if slot > MAX_LONG_OPERAND {
+            self.error("Too many global variables.");
+            return 0;
+        }
+        slot as u32
     }
 
     fn identifiers_equal(&self, a: &Token, b: &Token) -> bool {
         a.name == b.name
     }
 
-    fn resolve_local(&mut self, name: &Token) -> Option<u8> {
-        let locals = &self.current.locals;
-        for (i, local) in locals.iter().enumerate().rev() {
-            if self.identifiers_equal(name, &local.name) {
-                if local.depth == -1 {
-                    self.error("Can't read local variable in its own initializer.");
-                }
-                return Some(i as u8);
+    fn resolve_local(&mut self, name: &Token) -> Option<u32> {
+        match self.current.resolve_local(name) {
+            Ok(slot) => slot,
+            Err(message) => {
+                self.error(message);
+                None
+            }
+        }
+    }
+
+    fn resolve_upvalue(&mut self, name: &Token) -> Option<u8> {
+        match self.current.resolve_upvalue(name) {
+            Ok(index) => index,
+            Err(message) => {
+                self.error(message);
+                None
             }
         }
-        None
     }
 
     fn add_local(&mut self, name: Token<'src>) {
+        if self.current.locals.len() > MAX_LONG_OPERAND {
+            self.error("Too many local variables in function.");
+            return;
+        }
         let local = Local {
             name,
             depth: -1, //self.current.scope_depth,
+            is_captured: false,
         };
         self.current.locals.push(local);
     }
@@ -537,16 +999,20 @@ impl<'src> Compiler<'src> {
         self.add_local(name);
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn define_variable(&mut self, global: u32) {
         if self.current.scope_depth > 0 {
             self.mark_initialized();
             return;
         }
 
-        self.emit_byte(OpCode::DefineGlobal(global));
+        self.emit_byte(indexed(
+            global,
+            OpCode::DefineGlobal,
+            OpCode::DefineGlobalLong,
+        ));
     }
 
-    fn and_(&mut self, can_assign: bool) {
+    fn and_(&mut self, _can_assign: bool) {
         let end_jump = self.emit_jump(OpCode::JumpIfFalse(0));
 
         self.emit_byte(OpCode::Pop);
@@ -555,7 +1021,7 @@ impl<'src> Compiler<'src> {
         self.patch_jump(end_jump, OpCode::JumpIfFalse(0));
     }
 
-    fn or_(&mut self, can_assign: bool) {
+    fn or_(&mut self, _can_assign: bool) {
         let else_jump = self.emit_jump(OpCode::JumpIfFalse(0));
         let end_jump = self.emit_jump(OpCode::Jump(0));
 
@@ -598,64 +1064,88 @@ impl<'src> Compiler<'src> {
     }
 
     fn emit_byte(&mut self, byte: OpCode) {
-        let line = self.parser.previous.clone().unwrap().line as i32;
-        let chunk = self.current_chunk_mut();
-        chunk.write_chunk(byte, line);
+        self.emit_bytes(&[byte]);
     }
 
     fn emit_bytes(&mut self, bytes: &[OpCode]) {
-        let line = self.parser.previous.clone().unwrap().line as i32;
+        let token = self.parser.previous.clone().unwrap();
+        self.emit_bytes_at(&token, bytes);
+    }
+
+    // For instructions whose runtime errors belong to an earlier token, like
+    // a binary operator emitted after its right operand.
+    fn emit_bytes_at(&mut self, token: &Token, bytes: &[OpCode]) {
+        let position = Position {
+            line: token.line,
+            column: token.column,
+        };
         let chunk = self.current_chunk_mut();
         bytes.iter().for_each(|&b| {
-            chunk.write_chunk(b, line);
+            chunk.write_chunk(b, position);
         })
     }
 
-    fn emit_jump(&mut self, instruction: OpCode) -> u16 {
+    fn emit_loop(&mut self, loop_start: usize) {
+        // Offsets are in bytes, from the end of the `Loop` instruction.
+        let offset = self.current_chunk().code.len() + OpCode::Loop(0).size() - loop_start;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+        self.emit_byte(OpCode::Loop(offset as u16));
+    }
+
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        let offset = self.current_chunk().code.len();
         self.emit_byte(instruction);
-        let chunk = self.current_chunk();
-        chunk.code.len() as u16 - 1
+        offset
     }
 
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
-        self.emit_byte(OpCode::Constant(constant));
+        self.emit_byte(indexed(constant, OpCode::Constant, OpCode::ConstantLong));
     }
 
-    fn patch_jump(&mut self, offset: u16, new_opcode: OpCode) {
-        let offset = offset as usize;
-        let jump = self.current_chunk().code.len() - offset - 1;
+    fn patch_jump(&mut self, offset: usize, new_opcode: OpCode) {
+        let jump = self.current_chunk().code.len() - offset - new_opcode.size();
         if jump > u16::MAX as usize {
             self.error("Too much code to jump over.");
         }
-        let chunk = self.current_chunk_mut();
-        chunk.code[offset] = match new_opcode {
+        let patched = match new_opcode {
             OpCode::JumpIfFalse(_) => OpCode::JumpIfFalse(jump as u16),
             OpCode::Jump(_) => OpCode::Jump(jump as u16),
             _ => unreachable!("must not happen"),
-        }
+        };
+        self.current_chunk_mut().patch(offset, patched);
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
+    fn make_constant(&mut self, value: Value) -> u32 {
+        if let Value::Obj(obj) = value {
+            // Not reachable from the VM until the script is allocated.
+            self.vm.add_compiler_root(obj);
+        }
         let chunk = self.current_chunk_mut();
         let constant = chunk.add_constant(value);
-        if constant > u8::MAX as usize {
+        if constant > MAX_LONG_OPERAND {
             self.error("Too many constants in one chunk.");
             return 0;
         }
-        constant as u8
+        constant as u32
     }
 
     fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Return);
+        if self.current.typ == FunctionType::Initializer {
+            self.emit_bytes(&[OpCode::GetLocal(0), OpCode::Return]);
+        } else {
+            self.emit_bytes(&[OpCode::Nil, OpCode::Return]);
+        }
     }
 
     fn current_chunk(&self) -> &Chunk {
-        self.compiling_chunk
+        &self.current.function.chunk
     }
 
     fn current_chunk_mut(&mut self) -> &mut Chunk {
-        self.compiling_chunk
+        &mut self.current.function.chunk
     }
 
     fn error_at_current(&mut self, message: &str) {
@@ -672,22 +1162,51 @@ impl<'src> Compiler<'src> {
         self.error_at(token, message);
     }
 
+    // Only the first error until the parser resynchronizes is reported;
+    // later ones are usually knock-on effects of it.
     fn error_at(&mut self, token: Token, message: &str) {
-        self.parser.panic_mode = true;
         if self.parser.panic_mode {
             return;
         }
+        self.parser.panic_mode = true;
 
-        eprint!("[line {}] Error", token.line);
+        // Error tokens carry their message as the name, and their span can
+        // run to the end of the file, so they get no text at all.
+        let text = match token.typ {
+            TokenType::Error => &[][..],
+            _ => &self.source.as_bytes()[token.span.clone()],
+        };
+        self.parser.diagnostics.push(Diagnostic {
+            message: message.to_owned(),
+            severity: Severity::Error,
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            token: String::from_utf8_lossy(text).into_owned(),
+        });
+        self.parser.had_error = true;
+    }
+}
 
-        match token.typ {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error => { /* nothing */ }
-            _ => eprint!(" at {}", token.name),
-        }
+// Picks the one-byte form of an indexed instruction when the index fits.
+fn indexed(
+    index: u32,
+    short: impl FnOnce(u8) -> OpCode,
+    long: impl FnOnce(u32) -> OpCode,
+) -> OpCode {
+    match u8::try_from(index) {
+        Ok(index) => short(index),
+        Err(_) => long(index),
+    }
+}
 
-        eprintln!(": {}", message);
-        self.parser.had_error = true;
+fn synthetic_token(text: &'static str) -> Token<'static> {
+    Token {
+        typ: TokenType::Identifier,
+        name: text,
+        line: 0,
+        column: 0,
+        span: 0..0,
     }
 }
 
@@ -698,15 +1217,222 @@ impl<'src> Parser<'src> {
             previous: None,
             had_error: false,
             panic_mode: false,
+            diagnostics: Vec::new(),
         }
     }
 }
 
 impl<'src> Compiler2<'src> {
-    fn new() -> Self {
+    fn new(typ: FunctionType, name: Option<String>) -> Self {
+        // Slot zero holds the function being called, or the receiver in methods.
+        let slot_zero = Local {
+            name: if typ == FunctionType::Function || typ == FunctionType::Script {
+                synthetic_token("")
+            } else {
+                synthetic_token("this")
+            },
+            depth: 0,
+            is_captured: false,
+        };
         Compiler2 {
-            locals: Vec::new(),
+            enclosing: None,
+            function: Function::new(name),
+            typ,
+            locals: vec![slot_zero],
             scope_depth: 0,
+            loops: Vec::new(),
+            identifiers: HashMap::new(),
         }
     }
+
+    fn resolve_local(&self, name: &Token) -> Result<Option<u32>, &'static str> {
+        for (i, local) in self.locals.iter().enumerate().rev() {
+            if name.name == local.name.name {
+                if local.depth == -1 {
+                    return Err("Can't read local variable in its own initializer.");
+                }
+                return Ok(Some(i as u32));
+            }
+        }
+        Ok(None)
+    }
+
+    fn resolve_upvalue(&mut self, name: &Token) -> Result<Option<u8>, &'static str> {
+        let enclosing = match self.enclosing.as_mut() {
+            Some(enclosing) => enclosing,
+            None => return Ok(None),
+        };
+
+        let captured = if let Some(local) = enclosing.resolve_local(name)? {
+            enclosing.locals[local as usize].is_captured = true;
+            UpvalueIndex {
+                index: local,
+                is_local: true,
+            }
+        } else if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            UpvalueIndex {
+                index: upvalue as u32,
+                is_local: false,
+            }
+        } else {
+            return Ok(None);
+        };
+
+        self.add_upvalue(captured).map(Some)
+    }
+
+    fn add_upvalue(&mut self, upvalue: UpvalueIndex) -> Result<u8, &'static str> {
+        let upvalues = &mut self.function.upvalues;
+        if let Some(i) = upvalues.iter().position(|u| *u == upvalue) {
+            return Ok(i as u8);
+        }
+
+        if upvalues.len() > u8::MAX as usize {
+            return Err("Too many closure variables in function.");
+        }
+        upvalues.push(upvalue);
+        Ok((upvalues.len() - 1) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(source: &str) -> Vec<String> {
+        let mut vm = VM::new();
+        match Compiler::new(&mut vm, source).compile() {
+            Ok(_) => Vec::new(),
+            Err(diagnostics) => diagnostics.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn reports_each_independent_error_once() {
+        let cases: &[(&str, &[&str])] = &[
+            ("print 1;", &[]),
+            (
+                "print 1 print 2; print 3 print 4;",
+                &[
+                    "[line 1:9] Error at 'print': Expect ';' after value.",
+                    "[line 1:26] Error at 'print': Expect ';' after value.",
+                ],
+            ),
+            (
+                "var a = ;\nvar b = 1 +;\nvar c = 2;",
+                &[
+                    "[line 1:9] Error at ';': Expect expression.",
+                    "[line 2:12] Error at ';': Expect expression.",
+                ],
+            ),
+            (
+                "print (1 + 2 * ;\nprint 3;",
+                &["[line 1:16] Error at ';': Expect expression."],
+            ),
+            (
+                "print 1; @ print 2; var b = ;",
+                &[
+                    "[line 1:10] Error: Unexpected character.",
+                    "[line 1:29] Error at ';': Expect expression.",
+                ],
+            ),
+            (
+                "print \"unterminated;\nprint 1;",
+                &["[line 1:7] Error: Unterminated string."],
+            ),
+            (
+                "{ print 1 } print 2 print 3;",
+                &[
+                    "[line 1:11] Error at '}': Expect ';' after value.",
+                    "[line 1:21] Error at 'print': Expect ';' after value.",
+                ],
+            ),
+            (
+                "fun f() { return 1 }\nfun g( { }\nprint f();",
+                &[
+                    "[line 1:20] Error at '}': Expect ';' after return value.",
+                    "[line 2:8] Error at '{': Expect parameter name.",
+                ],
+            ),
+            (
+                "fun f() { var x = 1 var y = 2; }\nfun g() { print x }",
+                &[
+                    "[line 1:21] Error at 'var': Expect ';' after variable declaration.",
+                    "[line 2:19] Error at '}': Expect ';' after value.",
+                ],
+            ),
+            (
+                "class A { 1 m() {} }\nclass B < B {}",
+                &[
+                    "[line 1:11] Error at '1': Expect method name.",
+                    "[line 2:11] Error at 'B': A class can't inherit from itself.",
+                ],
+            ),
+            (
+                "break; continue;\nwhile (true) { break; }",
+                &[
+                    "[line 1:6] Error at ';': Can't use 'break' outside of a loop.",
+                    "[line 1:16] Error at ';': Can't use 'continue' outside of a loop.",
+                ],
+            ),
+            (
+                "print this;\nclass A { m() { super.m(); } }\nsuper.x;",
+                &[
+                    "[line 1:7] Error at 'this': Can't use 'this' outside of a class.",
+                    "[line 2:17] Error at 'super': Can't use 'super' in a class with no superclass.",
+                    "[line 3:1] Error at 'super': Can't use 'super' outside of a class.",
+                ],
+            ),
+            (
+                "return 1;\nclass A { init() { return 2; } }",
+                &[
+                    "[line 1:1] Error at 'return': Can't return from top-level code.",
+                    "[line 2:20] Error at 'return': Can't return a value from an initializer.",
+                ],
+            ),
+            (
+                "var a; var b;\na + b = 3;\n1 = 2;",
+                &[
+                    "[line 2:7] Error at '=': Invalid assignment target.",
+                    "[line 3:3] Error at '=': Invalid assignment target.",
+                ],
+            ),
+            (
+                "{ var a = 1; var a = 2; }\n{ var b = b; }",
+                &[
+                    "[line 1:18] Error at 'a': Already a variable with this name in this scope.",
+                    "[line 2:11] Error at 'b': Can't read local variable in its own initializer.",
+                ],
+            ),
+            ("print 1", &["[line 1:8] Error at end: Expect ';' after value."]),
+            (
+                "fun f() {\n  print 1;\n",
+                &["[line 3:1] Error at end: Expect '}' after block."],
+            ),
+        ];
+
+        for (source, expected) in cases {
+            assert_eq!(&errors(source), expected, "source: {:?}", source);
+        }
+    }
+
+    #[test]
+    fn identifier_names_share_one_constant_per_chunk() {
+        let mut vm = VM::new();
+        let function = Compiler::new(
+            &mut vm,
+            "var o; o.x = o.y; o.x = o.x; fun f() { return o.y; }",
+        )
+        .compile()
+        .unwrap();
+
+        let names: Vec<_> = function
+            .chunk
+            .constants
+            .values
+            .iter()
+            .filter_map(|value| value.string(vm.heap()))
+            .collect();
+        assert_eq!(names, ["x", "y"]);
+    }
 }
@@ -0,0 +1,444 @@
+//! Conversions between Rust values and Lox [`Value`]s, and natives whose
+//! arguments are converted for them.
+
+use crate::value::Value;
+use crate::vm::{Rooted, RuntimeError, VM};
+
+/// A Rust value that can be handed to Lox code. The result is rooted, so any
+/// number of converted values can be held before passing them to the VM.
+pub trait IntoValue {
+    fn into_value(self, vm: &mut VM) -> Rooted;
+}
+
+/// A Rust type that can be read back out of a Lox value.
+pub trait FromValue: Sized {
+    /// Name of the Lox type this accepts, for type-mismatch errors.
+    fn type_name() -> String;
+
+    /// Returns `None` if `value` does not hold this type.
+    fn from_value(value: Value, vm: &VM) -> Option<Self>;
+}
+
+impl IntoValue for Value {
+    fn into_value(self, vm: &mut VM) -> Rooted {
+        vm.root(self)
+    }
+}
+
+impl FromValue for Value {
+    fn type_name() -> String {
+        "value".to_owned()
+    }
+
+    fn from_value(value: Value, _vm: &VM) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, vm: &mut VM) -> Rooted {
+        vm.root(Value::Number(self))
+    }
+}
+
+impl FromValue for f64 {
+    fn type_name() -> String {
+        "number".to_owned()
+    }
+
+    fn from_value(value: Value, _vm: &VM) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+// Integers convert from numbers with no fractional part that fit the type.
+// `MAX` is one less than a power of two, which a 64-bit `MAX` rounds up to as
+// an `f64`, so the upper bound is that power of two, exclusive.
+macro_rules! integers {
+    ($($int:ty),*) => {
+        $(
+            impl IntoValue for $int {
+                fn into_value(self, vm: &mut VM) -> Rooted {
+                    vm.root(Value::Number(self as f64))
+                }
+            }
+
+            impl FromValue for $int {
+                fn type_name() -> String {
+                    "integer".to_owned()
+                }
+
+                fn from_value(value: Value, _vm: &VM) -> Option<Self> {
+                    match value {
+                        Value::Number(n)
+                            if n.fract() == 0.0
+                                && n >= <$int>::MIN as f64
+                                && n < <$int>::MAX as f64 + 1.0 =>
+                        {
+                            Some(n as $int)
+                        }
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+integers!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl IntoValue for bool {
+    fn into_value(self, vm: &mut VM) -> Rooted {
+        vm.root(Value::Boolean(self))
+    }
+}
+
+impl FromValue for bool {
+    fn type_name() -> String {
+        "boolean".to_owned()
+    }
+
+    fn from_value(value: Value, _vm: &VM) -> Option<Self> {
+        match value {
+            Value::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, vm: &mut VM) -> Rooted {
+        vm.root(Value::Nil)
+    }
+}
+
+impl FromValue for () {
+    fn type_name() -> String {
+        "nil".to_owned()
+    }
+
+    fn from_value(value: Value, _vm: &VM) -> Option<Self> {
+        match value {
+            Value::Nil => Some(()),
+            _ => None,
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, vm: &mut VM) -> Rooted {
+        vm.new_string(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, vm: &mut VM) -> Rooted {
+        vm.new_string(self)
+    }
+}
+
+impl FromValue for String {
+    fn type_name() -> String {
+        "string".to_owned()
+    }
+
+    fn from_value(value: Value, vm: &VM) -> Option<Self> {
+        value.string(vm.heap())
+    }
+}
+
+/// `None` is `nil`.
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self, vm: &mut VM) -> Rooted {
+        match self {
+            Some(value) => value.into_value(vm),
+            None => vm.root(Value::Nil),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn type_name() -> String {
+        format!("{} or nil", T::type_name())
+    }
+
+    fn from_value(value: Value, vm: &VM) -> Option<Self> {
+        match value {
+            Value::Nil => Some(None),
+            value => T::from_value(value, vm).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self, vm: &mut VM) -> Rooted {
+        vm.new_list(self)
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn type_name() -> String {
+        format!("list of {}", T::type_name())
+    }
+
+    fn from_value(value: Value, vm: &VM) -> Option<Self> {
+        let list = vm.heap().get(value.as_obj()?).as_list()?;
+        list.iter().map(|&item| T::from_value(item, vm)).collect()
+    }
+}
+
+/// What a typed native may return: any [`IntoValue`], or a `Result` of one to
+/// raise a runtime error.
+pub trait NativeResult {
+    fn into_result(self, vm: &mut VM) -> Result<Value, RuntimeError>;
+}
+
+// The VM pushes a native's result before it allocates again, so the result
+// needn't stay rooted.
+impl<T: IntoValue> NativeResult for T {
+    fn into_result(self, vm: &mut VM) -> Result<Value, RuntimeError> {
+        Ok(self.into_value(vm).value())
+    }
+}
+
+impl<T: IntoValue> NativeResult for Result<T, RuntimeError> {
+    fn into_result(self, vm: &mut VM) -> Result<Value, RuntimeError> {
+        self.map(|value| value.into_value(vm).value())
+    }
+}
+
+/// A Rust closure that [`VM::define_typed_native`] can install. Implemented for
+/// `Fn`s of up to six [`FromValue`] arguments returning a [`NativeResult`];
+/// `Args` is the tuple of argument types.
+pub trait NativeFunction<Args>: 'static {
+    fn arity(&self) -> usize;
+
+    /// Converts `args` and calls the closure. `name` is the native's name for
+    /// type-mismatch errors.
+    fn invoke(&self, vm: &mut VM, name: &str, args: &[Value]) -> Result<Value, RuntimeError>;
+}
+
+fn argument<T: FromValue>(
+    vm: &VM,
+    name: &str,
+    index: usize,
+    value: Value,
+) -> Result<T, RuntimeError> {
+    T::from_value(value, vm).ok_or_else(|| {
+        RuntimeError::new(format!(
+            "Expected {} as argument {} to '{}' but got {}.",
+            T::type_name(),
+            index + 1,
+            name,
+            value.type_name(vm.heap())
+        ))
+    })
+}
+
+macro_rules! native_functions {
+    ($(($($arg:ident),*)),*) => {
+        $(
+            impl<Fun, R, $($arg),*> NativeFunction<($($arg,)*)> for Fun
+            where
+                Fun: Fn($($arg),*) -> R + 'static,
+                R: NativeResult,
+                $($arg: FromValue),*
+            {
+                fn arity(&self) -> usize {
+                    <[&str]>::len(&[$(stringify!($arg)),*])
+                }
+
+                #[allow(non_snake_case, unused_variables, unused_mut)]
+                fn invoke(
+                    &self,
+                    vm: &mut VM,
+                    name: &str,
+                    args: &[Value],
+                ) -> Result<Value, RuntimeError> {
+                    let mut args = args.iter().copied().enumerate();
+                    $(
+                        let (index, value) = args.next().expect("arity was checked");
+                        let $arg = argument::<$arg>(vm, name, index, value)?;
+                    )*
+                    self($($arg),*).into_result(vm)
+                }
+            }
+        )*
+    };
+}
+
+native_functions!(
+    (),
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+    (A, B, C, D, E, F)
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::InterpretError;
+
+    fn round_trip<T: IntoValue + FromValue>(vm: &mut VM, value: T) -> Option<T> {
+        let value = value.into_value(vm);
+        T::from_value(value.value(), vm)
+    }
+
+    #[test]
+    fn rust_values_round_trip() {
+        let mut vm = VM::new();
+        vm.set_stress_gc(true);
+
+        assert_eq!(round_trip(&mut vm, 1.5), Some(1.5));
+        assert_eq!(round_trip(&mut vm, -7i32), Some(-7));
+        assert_eq!(round_trip(&mut vm, u64::from(u32::MAX)), Some(4294967295));
+        assert_eq!(round_trip(&mut vm, true), Some(true));
+        assert_eq!(round_trip(&mut vm, ()), Some(()));
+        assert_eq!(
+            round_trip(&mut vm, "lox".to_owned()).as_deref(),
+            Some("lox")
+        );
+        assert_eq!(round_trip(&mut vm, Some(2.0)), Some(Some(2.0)));
+        assert_eq!(round_trip(&mut vm, None::<f64>), Some(None));
+        assert_eq!(
+            round_trip(&mut vm, vec![vec!["a".to_owned()], vec![]]),
+            Some(vec![vec!["a".to_owned()], vec![]])
+        );
+
+        let list = vec![Some(1u8), None].into_value(&mut vm);
+        assert_eq!(list.value().display(vm.heap()).to_string(), "[1, nil]");
+    }
+
+    #[test]
+    fn converted_values_survive_until_a_call() {
+        let mut vm = VM::new();
+        vm.set_stress_gc(true);
+        vm.interpret("fun cat(a, b, c) { return a + b + to_string(c); }")
+            .unwrap();
+        let cat = vm.get_global("cat").unwrap();
+
+        let a = "left-".into_value(&mut vm);
+        let b = "right".to_owned().into_value(&mut vm);
+        let c = vec![vec![1.0], vec![2.0]].into_value(&mut vm);
+        let result = vm.call(cat.value(), &[a.value(), b.value(), c.value()]);
+        assert_eq!(
+            String::from_value(result.unwrap().value(), &vm).as_deref(),
+            Some("left-right[[1], [2]]")
+        );
+    }
+
+    #[test]
+    fn mismatched_values_do_not_convert() {
+        let mut vm = VM::new();
+        let held = "1".into_value(&mut vm);
+        let string = held.value();
+
+        assert_eq!(f64::from_value(string, &vm), None);
+        assert_eq!(i32::from_value(Value::Number(1.5), &vm), None);
+        assert_eq!(u8::from_value(Value::Number(256.0), &vm), None);
+        assert_eq!(u8::from_value(Value::Number(-1.0), &vm), None);
+        assert_eq!(i32::from_value(Value::Number(2f64.powi(31)), &vm), None);
+        assert_eq!(i64::from_value(Value::Number(2f64.powi(63)), &vm), None);
+        assert_eq!(u64::from_value(Value::Number(2f64.powi(64)), &vm), None);
+        assert_eq!(isize::from_value(Value::Number(2f64.powi(63)), &vm), None);
+        assert_eq!(usize::from_value(Value::Number(2f64.powi(64)), &vm), None);
+        assert_eq!(i64::from_value(Value::Number(f64::NAN), &vm), None);
+        assert_eq!(
+            i32::from_value(Value::Number(f64::from(i32::MAX)), &vm),
+            Some(i32::MAX)
+        );
+        assert_eq!(
+            i64::from_value(Value::Number(-(2f64.powi(63))), &vm),
+            Some(i64::MIN)
+        );
+        assert_eq!(
+            u64::from_value(Value::Number(2f64.powi(63)), &vm),
+            Some(1 << 63)
+        );
+        assert_eq!(bool::from_value(Value::Nil, &vm), None);
+        assert_eq!(String::from_value(Value::Number(1.0), &vm), None);
+        assert_eq!(Option::<f64>::from_value(string, &vm), None);
+        assert_eq!(Vec::<f64>::from_value(string, &vm), None);
+
+        let mixed = vec![Value::Number(1.0), string].into_value(&mut vm);
+        assert_eq!(Vec::<f64>::from_value(mixed.value(), &vm), None);
+        assert_eq!(
+            Vec::<Value>::from_value(mixed.value(), &vm).map(|v| v.len()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn typed_natives() {
+        let mut vm = VM::new();
+        vm.set_stress_gc(true);
+        vm.define_typed_native("hypot", |a: f64, b: f64| (a * a + b * b).sqrt());
+        vm.define_typed_native("repeat", |s: String, n: usize| s.repeat(n));
+        vm.define_typed_native("range", |n: u32| (0..n).collect::<Vec<_>>());
+        vm.define_typed_native("sum", |xs: Vec<f64>| xs.iter().sum::<f64>());
+        vm.define_typed_native("or_zero", |x: Option<f64>| x.unwrap_or(0.0));
+        vm.define_typed_native("checked_div", |a: f64, b: f64| {
+            if b == 0.0 {
+                Err(RuntimeError::new("Division by zero."))
+            } else {
+                Ok(a / b)
+            }
+        });
+        vm.interpret(
+            "var h = hypot(3, 4);
+            var r = repeat(\"ab\", 3);
+            var s = sum(range(5));
+            var z = or_zero(nil) + or_zero(2);
+            var d = checked_div(1, 4);
+            var l = to_string(range(3));",
+        )
+        .unwrap();
+
+        let global = |name| vm.get_global(name).unwrap().value();
+        assert_eq!(f64::from_value(global("h"), &vm), Some(5.0));
+        assert_eq!(
+            String::from_value(global("r"), &vm).as_deref(),
+            Some("ababab")
+        );
+        assert_eq!(f64::from_value(global("s"), &vm), Some(10.0));
+        assert_eq!(f64::from_value(global("z"), &vm), Some(2.0));
+        assert_eq!(f64::from_value(global("d"), &vm), Some(0.25));
+        assert_eq!(
+            String::from_value(global("l"), &vm).as_deref(),
+            Some("[0, 1, 2]")
+        );
+
+        let cases = [
+            (
+                "hypot(3, \"4\");",
+                "Expected number as argument 2 to 'hypot' but got string.",
+            ),
+            (
+                "repeat(\"ab\", 1.5);",
+                "Expected integer as argument 2 to 'repeat' but got number.",
+            ),
+            (
+                "sum(1);",
+                "Expected list of number as argument 1 to 'sum' but got number.",
+            ),
+            (
+                "or_zero(true);",
+                "Expected number or nil as argument 1 to 'or_zero' but got boolean.",
+            ),
+            ("checked_div(1, 0);", "Division by zero."),
+            ("hypot(1);", "Expected 2 arguments but got 1."),
+        ];
+        for (source, message) in cases {
+            match vm.interpret(source) {
+                Err(InterpretError::RuntimeError(err)) => assert_eq!(err.message, message),
+                other => panic!("{}: expected a runtime error, got {:?}", source, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found while compiling, tied to the token it was reported at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub severity: Severity,
+    pub line: usize,
+    pub column: usize,
+    /// Byte range of the token in the source.
+    pub span: Range<usize>,
+    /// The token's source text; empty at the end of the input and for
+    /// errors the scanner finds, which aren't at a whole token.
+    pub token: String,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}:{}] {}", self.line, self.column, self.severity)?;
+        if !self.token.is_empty() {
+            write!(f, " at '{}'", self.token)?;
+        } else if self.span.is_empty() {
+            write!(f, " at end")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{InterpretError, VM};
+
+    #[test]
+    fn display() {
+        let mut diagnostic = Diagnostic {
+            message: "Expect expression.".to_owned(),
+            severity: Severity::Error,
+            line: 3,
+            column: 9,
+            span: 20..21,
+            token: ";".to_owned(),
+        };
+        assert_eq!(
+            diagnostic.to_string(),
+            "[line 3:9] Error at ';': Expect expression."
+        );
+
+        diagnostic.token.clear();
+        assert_eq!(
+            diagnostic.to_string(),
+            "[line 3:9] Error: Expect expression."
+        );
+
+        diagnostic.span = 30..30;
+        assert_eq!(
+            diagnostic.to_string(),
+            "[line 3:9] Error at end: Expect expression."
+        );
+    }
+
+    #[test]
+    fn compile_errors_point_at_their_token() {
+        let source = "var a = 1;\nprint a +;\nprint \"open";
+        let mut vm = VM::new();
+        let diagnostics = match vm.interpret(source) {
+            Err(InterpretError::CompileError(diagnostics)) => diagnostics,
+            other => panic!("expected a compile error, got {:?}", other),
+        };
+
+        assert_eq!(
+            diagnostics,
+            [
+                Diagnostic {
+                    message: "Expect expression.".to_owned(),
+                    severity: Severity::Error,
+                    line: 2,
+                    column: 10,
+                    span: 20..21,
+                    token: ";".to_owned(),
+                },
+                Diagnostic {
+                    message: "Unterminated string.".to_owned(),
+                    severity: Severity::Error,
+                    line: 3,
+                    column: 7,
+                    span: 28..33,
+                    token: String::new(),
+                },
+            ]
+        );
+        assert_eq!(&source[diagnostics[0].span.clone()], ";");
+    }
+}
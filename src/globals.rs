@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use crate::memory::ObjRef;
+use crate::value::Value;
+
+/// Global variables, addressed by the slot the compiler resolved each name
+/// to. A slot exists as soon as any code mentions the name; it holds no
+/// value until a `var`, `fun` or `class` declaration runs.
+pub(crate) struct Globals {
+    slots: HashMap<ObjRef, usize>,
+    names: Vec<ObjRef>,
+    values: Vec<Option<Value>>,
+}
+
+impl Globals {
+    pub(crate) fn new() -> Self {
+        Globals {
+            slots: HashMap::new(),
+            names: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    /// Returns the slot for `name`, reserving a new one on first use.
+    pub(crate) fn slot(&mut self, name: ObjRef) -> usize {
+        if let Some(&slot) = self.slots.get(&name) {
+            return slot;
+        }
+        self.names.push(name);
+        self.values.push(None);
+        self.slots.insert(name, self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    pub(crate) fn name(&self, slot: usize) -> ObjRef {
+        self.names[slot]
+    }
+
+    pub(crate) fn get(&self, slot: usize) -> Option<Value> {
+        self.values[slot]
+    }
+
+    pub(crate) fn lookup(&self, name: ObjRef) -> Option<Value> {
+        self.slots.get(&name).and_then(|&slot| self.values[slot])
+    }
+
+    pub(crate) fn define(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
+    }
+
+    /// Assigns to an already defined global; returns false if it is undefined.
+    pub(crate) fn set(&mut self, slot: usize, value: Value) -> bool {
+        match &mut self.values[slot] {
+            Some(var) => {
+                *var = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn names(&self) -> &[ObjRef] {
+        &self.names
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = Value> + '_ {
+        self.values.iter().flatten().copied()
+    }
+}
@@ -3,7 +3,14 @@
 pub mod chunk;
 pub mod common;
 pub mod compiler;
+pub mod convert;
+pub mod diagnostic;
+mod globals;
+pub mod loxc;
+pub mod memory;
+pub mod native;
 pub mod object;
 pub mod scanner;
 pub mod value;
+pub mod verify;
 pub mod vm;
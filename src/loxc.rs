@@ -0,0 +1,498 @@
+//! The `.loxc` compiled bytecode format.
+//!
+//! A file is the magic bytes `LOXC`, a little-endian `u16` format version,
+//! the names of the compiling VM's global slots, then the script's chunk.
+//! Global instructions are relocated to the loading VM's slots for those
+//! names as the file is read. A chunk is its code bytes, its line table as
+//! `(offset, line, column)` runs, and its constant pool; function constants
+//! carry their name, arity, upvalue descriptors and their own chunk. Integers
+//! are little-endian `u32`s and strings are a length followed by UTF-8.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+use crate::chunk::{Chunk, OpCode, Position};
+use crate::object::{Function, Object, UpvalueIndex};
+use crate::value::Value;
+use crate::verify::VerifyError;
+use crate::vm::{Rooted, VM};
+
+const MAGIC: &[u8; 4] = b"LOXC";
+const VERSION: u16 = 1;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+// How deeply function constants may nest, so a crafted file can't recurse the
+// reader off the end of the stack.
+const MAX_NESTING: usize = 256;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("not a .loxc file")]
+    BadMagic,
+    #[error("unsupported .loxc version {0}")]
+    UnsupportedVersion(u16),
+    #[error("malformed .loxc file: {0}")]
+    Malformed(&'static str),
+    #[error("global '{0}' does not fit its instruction in this VM")]
+    Relocation(String),
+    #[error("invalid .loxc file: {0}")]
+    Invalid(#[from] VerifyError),
+}
+
+impl Chunk {
+    /// Writes this chunk, compiled by `vm`, as a complete `.loxc` file.
+    pub fn write_to(&self, out: impl Write, vm: &VM) -> io::Result<()> {
+        let mut writer = Writer { out, vm };
+        writer.out.write_all(MAGIC)?;
+        writer.out.write_all(&VERSION.to_le_bytes())?;
+        let globals: Vec<_> = vm.global_names().collect();
+        writer.u32(globals.len())?;
+        for name in globals {
+            writer.bytes(name.as_bytes())?;
+        }
+        writer.chunk(self)
+    }
+
+    /// Reads and verifies a script written by [`Chunk::write_to`], allocating
+    /// its string and function constants in `vm`. Like [`VM::compile`], this
+    /// returns a script to run with [`VM::call`], kept alive by its handle.
+    pub fn read_from(input: impl Read, vm: &mut VM) -> Result<Rooted, LoadError> {
+        let mut reader = Reader {
+            input,
+            vm,
+            globals: Vec::new(),
+            depth: 0,
+        };
+        let result = reader.file().and_then(|chunk| {
+            chunk.verify(reader.vm)?;
+            let mut function = Function::new(None);
+            function.chunk = chunk;
+            Ok(reader.vm.new_script(function))
+        });
+        // The script holds the constants now, or they are garbage.
+        reader.vm.clear_compiler_roots();
+        result
+    }
+}
+
+struct Writer<'a, W> {
+    out: W,
+    vm: &'a VM,
+}
+
+impl<W: Write> Writer<'_, W> {
+    fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.out.write_all(&[value])
+    }
+
+    fn u32(&mut self, value: usize) -> io::Result<()> {
+        let value = u32::try_from(value)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "value too large"))?;
+        self.out.write_all(&value.to_le_bytes())
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.u32(bytes.len())?;
+        self.out.write_all(bytes)
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) -> io::Result<()> {
+        self.bytes(&chunk.code)?;
+
+        let runs: Vec<_> = chunk.lines.runs().collect();
+        self.u32(runs.len())?;
+        for (offset, position) in runs {
+            self.u32(offset)?;
+            self.u32(position.line)?;
+            self.u32(position.column)?;
+        }
+
+        self.u32(chunk.constants.values.len())?;
+        for &constant in &chunk.constants.values {
+            self.constant(constant)?;
+        }
+        Ok(())
+    }
+
+    fn constant(&mut self, value: Value) -> io::Result<()> {
+        match value {
+            Value::Nil => self.u8(TAG_NIL),
+            Value::Boolean(false) => self.u8(TAG_FALSE),
+            Value::Boolean(true) => self.u8(TAG_TRUE),
+            Value::Number(number) => {
+                self.u8(TAG_NUMBER)?;
+                self.out.write_all(&number.to_le_bytes())
+            }
+            Value::Obj(obj) => match self.vm.heap().get(obj) {
+                Object::String(string) => {
+                    self.u8(TAG_STRING)?;
+                    self.bytes(string.as_bytes())
+                }
+                Object::Function(function) => {
+                    self.u8(TAG_FUNCTION)?;
+                    self.function(function)
+                }
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "constant cannot be serialized",
+                )),
+            },
+        }
+    }
+
+    fn function(&mut self, function: &Function) -> io::Result<()> {
+        match &function.name {
+            Some(name) => {
+                self.u8(1)?;
+                self.bytes(name.as_bytes())?;
+            }
+            None => self.u8(0)?,
+        }
+        self.u32(function.arity)?;
+        self.u32(function.upvalues.len())?;
+        for upvalue in &function.upvalues {
+            self.u32(upvalue.index as usize)?;
+            self.u8(upvalue.is_local as u8)?;
+        }
+        self.chunk(&function.chunk)
+    }
+}
+
+struct Reader<'a, R> {
+    input: R,
+    vm: &'a mut VM,
+    // Each saved global slot's name and its slot in `vm`.
+    globals: Vec<(String, usize)>,
+    // How many function constants enclose the chunk being read.
+    depth: usize,
+}
+
+impl<R: Read> Reader<'_, R> {
+    fn file(&mut self) -> Result<Chunk, LoadError> {
+        let mut magic = [0; 4];
+        self.input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+        let mut version = [0; 2];
+        self.input.read_exact(&mut version)?;
+        match u16::from_le_bytes(version) {
+            VERSION => {}
+            version => return Err(LoadError::UnsupportedVersion(version)),
+        }
+        for _ in 0..self.u32()? {
+            let name = self.string()?;
+            let slot = self.vm.global_slot_for(&name);
+            self.globals.push((name, slot));
+        }
+        self.chunk()
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        let mut byte = [0];
+        self.input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        let mut bytes = [0; 4];
+        self.input.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes) as usize)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, LoadError> {
+        let len = self.u32()?;
+        let mut bytes = Vec::new();
+        // `take` so a corrupt length can't make us allocate gigabytes up front.
+        (&mut self.input).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        String::from_utf8(self.bytes()?).map_err(|_| LoadError::Malformed("string is not UTF-8"))
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, LoadError> {
+        let mut chunk = Chunk::new();
+        chunk.code = self.bytes()?;
+        self.relocate_globals(&mut chunk)?;
+
+        let mut last = None;
+        for _ in 0..self.u32()? {
+            let offset = self.u32()?;
+            let position = Position {
+                line: self.u32()?,
+                column: self.u32()?,
+            };
+            if offset >= chunk.code.len() || last.map_or(offset != 0, |last| offset <= last) {
+                return Err(LoadError::Malformed("line table out of order"));
+            }
+            chunk.lines.push(offset, position);
+            last = Some(offset);
+        }
+        if last.is_none() && !chunk.code.is_empty() {
+            return Err(LoadError::Malformed("missing line table"));
+        }
+
+        for _ in 0..self.u32()? {
+            let constant = self.constant()?;
+            chunk.add_constant(constant);
+        }
+        Ok(chunk)
+    }
+
+    fn relocate_globals(&self, chunk: &mut Chunk) -> Result<(), LoadError> {
+        let relocated: Vec<_> = chunk
+            .instructions()
+            .filter_map(|(offset, op)| match op {
+                OpCode::GetGlobal(slot) | OpCode::DefineGlobal(slot) | OpCode::SetGlobal(slot) => {
+                    Some((offset, op, slot as usize))
+                }
+                OpCode::GetGlobalLong(slot)
+                | OpCode::DefineGlobalLong(slot)
+                | OpCode::SetGlobalLong(slot) => Some((offset, op, slot as usize)),
+                _ => None,
+            })
+            .collect();
+
+        for (offset, op, slot) in relocated {
+            let (name, slot) = self
+                .globals
+                .get(slot)
+                .ok_or(LoadError::Malformed("unknown global slot"))?;
+            let short = || u8::try_from(*slot).map_err(|_| LoadError::Relocation(name.clone()));
+            let long = *slot as u32;
+            let op = match op {
+                OpCode::GetGlobal(_) => OpCode::GetGlobal(short()?),
+                OpCode::DefineGlobal(_) => OpCode::DefineGlobal(short()?),
+                OpCode::SetGlobal(_) => OpCode::SetGlobal(short()?),
+                OpCode::GetGlobalLong(_) => OpCode::GetGlobalLong(long),
+                OpCode::DefineGlobalLong(_) => OpCode::DefineGlobalLong(long),
+                OpCode::SetGlobalLong(_) => OpCode::SetGlobalLong(long),
+                _ => unreachable!(),
+            };
+            chunk.patch(offset, op);
+        }
+        Ok(())
+    }
+
+    fn constant(&mut self) -> Result<Value, LoadError> {
+        let value = match self.u8()? {
+            TAG_NIL => Value::Nil,
+            TAG_FALSE => Value::Boolean(false),
+            TAG_TRUE => Value::Boolean(true),
+            TAG_NUMBER => {
+                let mut bytes = [0; 8];
+                self.input.read_exact(&mut bytes)?;
+                Value::Number(f64::from_le_bytes(bytes))
+            }
+            TAG_STRING => {
+                let string = self.string()?;
+                Value::Obj(self.vm.intern(string))
+            }
+            TAG_FUNCTION => {
+                let function = self.function()?;
+                Value::Obj(self.vm.alloc(Object::Function(function)))
+            }
+            _ => return Err(LoadError::Malformed("unknown constant tag")),
+        };
+        if let Value::Obj(obj) = value {
+            // Nothing else refers to it until the script is allocated.
+            self.vm.add_compiler_root(obj);
+        }
+        Ok(value)
+    }
+
+    fn function(&mut self) -> Result<Function, LoadError> {
+        if self.depth == MAX_NESTING {
+            return Err(LoadError::Malformed("functions nested too deeply"));
+        }
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            _ => return Err(LoadError::Malformed("bad function name")),
+        };
+        let mut function = Function::new(name);
+        function.arity = self.u32()?;
+        for _ in 0..self.u32()? {
+            let index = self.u32()? as u32;
+            let is_local = match self.u8()? {
+                0 => false,
+                1 => true,
+                _ => return Err(LoadError::Malformed("bad upvalue descriptor")),
+            };
+            function.upvalues.push(UpvalueIndex { index, is_local });
+        }
+        self.depth += 1;
+        let chunk = self.chunk();
+        self.depth -= 1;
+        function.chunk = chunk?;
+        Ok(function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+
+    fn compile(vm: &mut VM, source: &str) -> Vec<u8> {
+        let function = Compiler::new(vm, source).compile().unwrap();
+        let mut bytes = Vec::new();
+        function.chunk.write_to(&mut bytes, vm).unwrap();
+        bytes
+    }
+
+    fn script_chunk<'vm>(vm: &'vm VM, script: &Rooted) -> &'vm Chunk {
+        let closure = vm.heap().get(script.value().as_obj().unwrap());
+        let function = vm.heap().get(closure.as_closure().unwrap().function);
+        &function.as_function().unwrap().chunk
+    }
+
+    #[test]
+    fn round_trips_constants_and_functions() {
+        let mut vm = VM::new();
+        let bytes = compile(
+            &mut vm,
+            "fun f(a, b) { return \"s\" + a; } var n = 1.5; var t = true;",
+        );
+
+        let mut loaded = VM::new();
+        let script = Chunk::read_from(bytes.as_slice(), &mut loaded).unwrap();
+        let chunk = script_chunk(&loaded, &script);
+        let mut again = Vec::new();
+        chunk.write_to(&mut again, &loaded).unwrap();
+
+        assert_eq!(again, bytes);
+        let function = chunk
+            .constants
+            .values
+            .iter()
+            .find_map(|value| loaded.heap().get(value.as_obj()?).as_function())
+            .unwrap();
+        assert_eq!(function.name.as_deref(), Some("f"));
+        assert_eq!(function.arity, 2);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut vm = VM::new();
+        let mut bytes = compile(&mut vm, "print 1;");
+
+        let result = Chunk::read_from(&b"LOXX\x01\x00"[..], &mut vm);
+        assert!(matches!(result, Err(LoadError::BadMagic)));
+
+        bytes[4] = 9;
+        let result = Chunk::read_from(bytes.as_slice(), &mut vm);
+        assert!(matches!(result, Err(LoadError::UnsupportedVersion(9))));
+    }
+
+    #[test]
+    fn rejects_chunks_that_do_not_verify() {
+        let mut vm = VM::new();
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Nil, Position { line: 1, column: 1 });
+        let mut bytes = Vec::new();
+        chunk.write_to(&mut bytes, &vm).unwrap();
+
+        let result = Chunk::read_from(bytes.as_slice(), &mut vm);
+        assert!(matches!(result, Err(LoadError::Invalid(_))));
+    }
+
+    #[test]
+    fn loaded_script_outlives_other_work() {
+        let mut vm = VM::new();
+        let bytes = compile(
+            &mut vm,
+            "var s = \"lo\" + \"x\"; fun f() { return s; } var r = f();",
+        );
+
+        let mut loaded = VM::new();
+        loaded.set_stress_gc(true);
+        let script = Chunk::read_from(bytes.as_slice(), &mut loaded).unwrap();
+        loaded.interpret("var garbage = \"a\" + \"b\";").unwrap();
+        loaded.compile("var more = \"c\";").unwrap();
+        loaded.call(script.value(), &[]).unwrap();
+
+        let r = loaded.get_global("r").unwrap();
+        assert_eq!(r.value().string(loaded.heap()).as_deref(), Some("lox"));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let mut vm = VM::new();
+        let bytes = compile(&mut vm, "fun f() { return \"x\"; } print f();");
+
+        for len in 0..bytes.len() {
+            let result = Chunk::read_from(&bytes[..len], &mut vm);
+            assert!(result.is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_deeply_nested_functions() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend(0u32.to_le_bytes());
+        // An empty chunk whose one constant is a function, over and over; the
+        // file never ends because the reader should give up long before.
+        for _ in 0..200_000 {
+            bytes.extend([0; 4]);
+            bytes.extend([0; 4]);
+            bytes.extend(1u32.to_le_bytes());
+            bytes.push(TAG_FUNCTION);
+            bytes.push(0);
+            bytes.extend([0; 4]);
+            bytes.extend([0; 4]);
+        }
+
+        let mut vm = VM::new();
+        let result = Chunk::read_from(bytes.as_slice(), &mut vm);
+        assert!(matches!(
+            result,
+            Err(LoadError::Malformed("functions nested too deeply"))
+        ));
+    }
+
+    #[test]
+    fn failed_load_does_not_keep_its_constants_alive() {
+        let mut vm = VM::new();
+        let bytes = compile(&mut vm, "print \"a\" + \"b\"; print \"c\";");
+
+        let mut loaded = VM::new();
+        loaded.set_stress_gc(true);
+        let before = loaded.heap().len();
+        let result = Chunk::read_from(&bytes[..bytes.len() - 1], &mut loaded);
+        assert!(result.is_err());
+
+        loaded.new_string("d");
+        assert_eq!(loaded.heap().len(), before + 1);
+    }
+
+    #[test]
+    fn short_global_slot_that_no_longer_fits_is_an_error() {
+        let mut vm = VM::new();
+        let bytes = compile(&mut vm, "var late = 1;");
+
+        let mut crowded = VM::new();
+        let mut source = String::new();
+        for i in 0..300 {
+            source.push_str(&format!("var g{} = {};", i, i));
+        }
+        crowded.interpret(&source).unwrap();
+
+        let result = Chunk::read_from(bytes.as_slice(), &mut crowded);
+        assert!(matches!(result, Err(LoadError::Relocation(name)) if name == "late"));
+    }
+}
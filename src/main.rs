@@ -1,20 +1,48 @@
-use rlox::vm::VM;
-use std::io::{BufRead, Write};
+use rlox::chunk::Chunk;
+use rlox::common::VmOptions;
+use rlox::vm::{InterpretError, VM};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::process;
+
+const USAGE: &str = "Usage: rlox [options] [path]
+       rlox [options] run <file.loxc>
+       rlox [options] compile <file.lox> -o <file.loxc>
+
+Options:
+  --trace        disassemble each instruction as it runs
+  --print-code   disassemble each function after compiling it
+  --dump-tokens  print every token the compiler scans
+  --stress-gc    collect garbage before every allocation";
 
 fn main() -> anyhow::Result<()> {
-    let mut it = std::env::args();
-    if it.len() == 1 {
-        repl()?;
-    } else if it.len() == 2 {
-        run_file(&it.nth(1).unwrap())?;
-    } else {
-        eprintln!("Usage: rlox [path]");
+    let mut options = VmOptions::new();
+    let mut args = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--trace" => options = options.trace_execution(true),
+            "--print-code" => options = options.print_code(true),
+            "--dump-tokens" => options = options.dump_tokens(true),
+            "--stress-gc" => options = options.stress_gc(true),
+            _ => args.push(arg),
+        }
+    }
+
+    let mut vm = VM::with_options(options);
+    vm.set_error_output(Box::new(io::stderr()));
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => repl(&mut vm)?,
+        ["compile", input, "-o", output] => compile_file(&mut vm, input, output)?,
+        ["run", path] => run_compiled(&mut vm, path)?,
+        [path] => run_file(&mut vm, path)?,
+        _ => eprintln!("{}", USAGE),
     }
     Ok(())
 }
 
-fn repl() -> std::io::Result<()> {
-    let mut vm = VM::new();
+fn repl(vm: &mut VM) -> std::io::Result<()> {
     let stdin = std::io::stdin();
     let mut handle = stdin.lock();
     let mut line = String::new();
@@ -29,13 +57,45 @@ fn repl() -> std::io::Result<()> {
             return Ok(());
         }
 
+        // The VM reports errors itself; carry on with the next line.
         let _ = vm.interpret(&line);
     }
 }
 
-fn run_file(path: &str) -> anyhow::Result<()> {
-    let mut vm = VM::new();
+fn run_file(vm: &mut VM, path: &str) -> anyhow::Result<()> {
     let source = std::fs::read_to_string(path)?;
-    vm.interpret(&source)?;
+    if let Err(err) = vm.interpret(&source) {
+        exit_with(err);
+    }
     Ok(())
 }
+
+fn compile_file(vm: &mut VM, input: &str, output: &str) -> anyhow::Result<()> {
+    let source = std::fs::read_to_string(input)?;
+    let script = vm.compile(&source).unwrap_or_else(|err| exit_with(err));
+    let script = script.value().as_obj().unwrap();
+    let closure = vm.heap().get(script).as_closure().unwrap();
+    let function = vm.heap().get(closure.function).as_function().unwrap();
+    let mut out = BufWriter::new(File::create(output)?);
+    function.chunk.write_to(&mut out, vm)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn run_compiled(vm: &mut VM, path: &str) -> anyhow::Result<()> {
+    let script = Chunk::read_from(BufReader::new(File::open(path)?), vm)?;
+    if let Err(err) = vm.call(script.value(), &[]) {
+        // Unlike `interpret`, `call` leaves reporting to us.
+        eprintln!("{}", err);
+        exit_with(InterpretError::RuntimeError(err));
+    }
+    Ok(())
+}
+
+// The VM has already reported `err`, so just exit with clox's status codes.
+fn exit_with(err: InterpretError) -> ! {
+    match err {
+        InterpretError::CompileError(_) | InterpretError::InvalidChunk(_) => process::exit(65),
+        InterpretError::RuntimeError(_) | InterpretError::OutOfFuel => process::exit(70),
+    }
+}
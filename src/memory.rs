@@ -0,0 +1,135 @@
+use crate::object::Object;
+use crate::value::Value;
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+/// A handle to an object owned by a `Heap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+struct Slot {
+    marked: bool,
+    size: usize,
+    object: Object,
+}
+
+/// Mark-and-sweep object heap. Callers mark their roots, call
+/// `trace_references`, drop any weak references to unmarked objects, then
+/// `sweep` frees everything left unmarked.
+pub struct Heap {
+    slots: Vec<Option<Slot>>,
+    free: Vec<usize>,
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    stress: bool,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            slots: Vec::new(),
+            free: Vec::new(),
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            stress: false,
+        }
+    }
+
+    /// Collect on every allocation, to shake out missing roots.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn alloc(&mut self, object: Object) -> ObjRef {
+        let size = object.size();
+        self.bytes_allocated += size;
+
+        let slot = Slot {
+            marked: false,
+            size,
+            object,
+        };
+        match self.free.pop() {
+            Some(index) => {
+                self.slots[index] = Some(slot);
+                ObjRef(index)
+            }
+            None => {
+                self.slots.push(Some(slot));
+                ObjRef(self.slots.len() - 1)
+            }
+        }
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Object {
+        &self.slots[obj.0].as_ref().expect("use after free").object
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Object {
+        &mut self.slots[obj.0].as_mut().expect("use after free").object
+    }
+
+    /// Number of live objects.
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        let slot = self.slots[obj.0].as_mut().expect("marking freed object");
+        if slot.marked {
+            return;
+        }
+        slot.marked = true;
+        self.gray.push(obj);
+    }
+
+    pub fn is_marked(&self, obj: ObjRef) -> bool {
+        self.slots[obj.0].as_ref().is_some_and(|slot| slot.marked)
+    }
+
+    pub fn trace_references(&mut self) {
+        let mut children = Vec::new();
+        while let Some(obj) = self.gray.pop() {
+            self.get(obj).trace(&mut children);
+            for child in children.drain(..) {
+                self.mark_object(child);
+            }
+        }
+    }
+
+    pub fn sweep(&mut self) {
+        for (index, entry) in self.slots.iter_mut().enumerate() {
+            match entry {
+                Some(slot) if slot.marked => slot.marked = false,
+                Some(slot) => {
+                    self.bytes_allocated -= slot.size;
+                    *entry = None;
+                    self.free.push(index);
+                }
+                None => {}
+            }
+        }
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+    }
+}
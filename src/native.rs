@@ -0,0 +1,35 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::value::Value;
+use crate::vm::{RuntimeError, VM};
+
+pub(crate) fn define_natives(vm: &mut VM) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("type_of", 1, type_of);
+    vm.define_native("to_string", 1, to_string);
+    vm.define_native("len", 1, len);
+}
+
+fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| RuntimeError::new(err.to_string()))?;
+    Ok(Value::Number(now.as_secs_f64()))
+}
+
+fn type_of(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let name = args[0].type_name(vm.heap());
+    Ok(vm.new_string(name).value())
+}
+
+fn to_string(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let s = args[0].display(vm.heap()).to_string();
+    Ok(vm.new_string(s).value())
+}
+
+fn len(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0].string(vm.heap()) {
+        Some(s) => Ok(Value::Number(s.chars().count() as f64)),
+        None => Err(RuntimeError::new("Argument to 'len' must be a string.")),
+    }
+}
@@ -1,15 +1,79 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::memory::ObjRef;
+use crate::value::Value;
+use crate::vm::{RuntimeError, VM};
+
 #[derive(Debug)]
 pub enum Object {
     String(String),
+    Function(Function),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Native),
+    List(Vec<Value>),
 }
 
-impl Object {
-    pub fn values_equal(a: &Object, b: &Object) -> bool {
-        match (a, b) {
-            (Self::String(a), Self::String(b)) => a == b,
-        }
-    }
+#[derive(Debug)]
+pub struct Function {
+    pub arity: usize,
+    pub chunk: Chunk,
+    pub name: Option<String>,
+    pub upvalues: Vec<UpvalueIndex>,
+}
+
+// Where a closure captures each upvalue from when it is created: a local slot
+// of the enclosing function, or one of the enclosing closure's upvalues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueIndex {
+    pub index: u32,
+    pub is_local: bool,
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<ObjRef, ObjRef>,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+pub type NativeFn = Rc<dyn Fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>>;
+
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
 
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+impl Object {
     pub fn is_string(&self) -> bool {
         matches!(*self, Object::String(_))
     }
@@ -17,6 +81,176 @@ impl Object {
     pub fn as_str(&self) -> &str {
         match self {
             Object::String(s) => s.as_str(),
+            _ => panic!("not a string: {:?}", self),
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&Function> {
+        match self {
+            Object::Function(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self) -> Option<&Closure> {
+        match self {
+            Object::Closure(c) => Some(c),
+            _ => None,
         }
     }
+
+    pub fn as_upvalue_mut(&mut self) -> Option<&mut Upvalue> {
+        match self {
+            Object::Upvalue(u) => Some(u),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self) -> Option<&Class> {
+        match self {
+            Object::Class(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn as_class_mut(&mut self) -> Option<&mut Class> {
+        match self {
+            Object::Class(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<&Instance> {
+        match self {
+            Object::Instance(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance_mut(&mut self) -> Option<&mut Instance> {
+        match self {
+            Object::Instance(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Object::List(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Pushes every object this one refers to.
+    pub fn trace(&self, out: &mut Vec<ObjRef>) {
+        let mut value = |value: &Value| {
+            if let Value::Obj(obj) = value {
+                out.push(*obj);
+            }
+        };
+        match self {
+            Object::String(_) | Object::Native(_) => {}
+            Object::Function(function) => function.chunk.constants.values.iter().for_each(value),
+            Object::Closure(closure) => {
+                out.push(closure.function);
+                out.extend(&closure.upvalues);
+            }
+            Object::Upvalue(Upvalue::Closed(closed)) => value(closed),
+            Object::Upvalue(Upvalue::Open(_)) => {}
+            Object::Class(class) => {
+                out.extend(class.methods.keys());
+                out.extend(class.methods.values());
+            }
+            Object::Instance(instance) => {
+                instance.fields.values().for_each(value);
+                out.extend(instance.fields.keys());
+                out.push(instance.class);
+            }
+            Object::BoundMethod(bound) => {
+                value(&bound.receiver);
+                out.push(bound.method);
+            }
+            Object::List(values) => values.iter().for_each(value),
+        }
+    }
+
+    /// Approximate number of bytes owned by this object, for GC pacing.
+    pub fn size(&self) -> usize {
+        use std::mem::size_of;
+
+        let owned = match self {
+            Object::String(s) => s.capacity(),
+            Object::Function(function) => {
+                let chunk = &function.chunk;
+                chunk.code.capacity()
+                    + chunk.lines.size()
+                    + chunk.constants.values.capacity() * size_of::<Value>()
+            }
+            Object::Closure(closure) => closure.upvalues.capacity() * size_of::<ObjRef>(),
+            Object::Class(class) => class.methods.capacity() * size_of::<(ObjRef, ObjRef)>(),
+            Object::Instance(instance) => instance.fields.capacity() * size_of::<(ObjRef, Value)>(),
+            Object::List(values) => values.capacity() * size_of::<Value>(),
+            Object::Upvalue(_) | Object::BoundMethod(_) | Object::Native(_) => 0,
+        };
+        size_of::<Object>() + owned
+    }
+}
+
+impl Function {
+    pub fn new(name: Option<String>) -> Self {
+        Function {
+            arity: 0,
+            chunk: Chunk::new(),
+            name,
+            upvalues: Vec::new(),
+        }
+    }
+}
+
+impl Closure {
+    pub fn new(function: ObjRef) -> Self {
+        Closure {
+            function,
+            upvalues: Vec::new(),
+        }
+    }
+}
+
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl Class {
+    pub fn new(name: String) -> Self {
+        Class {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+impl Instance {
+    pub fn new(class: ObjRef) -> Self {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: ObjRef) -> Self {
+        BoundMethod { receiver, method }
+    }
+}
+
+impl std::fmt::Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
 }
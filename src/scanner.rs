@@ -1,8 +1,13 @@
+use std::ops::Range;
+
 pub struct Scanner<'src> {
     source: &'src str,
     start: usize,
     current: usize,
     line: usize,
+    line_start: usize,   // offset of the first byte on `line`
+    start_line: usize,   // line the token being scanned starts on
+    start_column: usize, // column of the token being scanned
 }
 
 #[derive(Debug, Clone)]
@@ -10,6 +15,9 @@ pub struct Token<'src> {
     pub typ: TokenType,
     pub name: &'src str,
     pub line: usize,
+    pub column: usize,
+    /// Byte range of the lexeme in the source, even for error tokens.
+    pub span: Range<usize>,
 }
 
 #[rustfmt::skip]
@@ -24,7 +32,7 @@ pub enum TokenType {
     // Literals.
     Identifier, String, Number,
     // Keywords.
-    And, Class, Else, False, For, Fun, If, Nil,
+    And, Break, Class, Continue, Else, False, For, Fun, If, Nil,
     Or, Print, Return, Super, This, True, Var, While,
 
     Error, Eof,
@@ -41,12 +49,15 @@ impl<'src> Scanner<'src> {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
     pub fn advance(&mut self) -> Option<char> {
         self.current += 1;
-        if self.current - 1 < self.source.as_bytes().len() {
+        if self.current - 1 < self.source.len() {
             Some(self.source.as_bytes()[self.current - 1] as char)
         } else {
             None
@@ -88,8 +99,8 @@ impl<'src> Scanner<'src> {
                         self.advance();
                     }
                     '\n' => {
-                        self.line += 1;
                         self.advance();
+                        self.new_line();
                     }
                     '/' => {
                         if let Some('/') = self.peek_next() {
@@ -111,6 +122,8 @@ impl<'src> Scanner<'src> {
     pub fn scan_token(&mut self) -> Token<'src> {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.start - self.line_start + 1;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -120,7 +133,7 @@ impl<'src> Scanner<'src> {
         if c.is_ascii_alphabetic() || c == '_' {
             return self.identifier();
         }
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             return self.number();
         }
         match c {
@@ -176,10 +189,10 @@ impl<'src> Scanner<'src> {
 
     fn string(&mut self) -> Token<'src> {
         while let Some(c) = self.peek().filter(|c| *c != '"') {
+            self.advance();
             if c == '\n' {
-                self.line += 1;
+                self.new_line();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
@@ -192,14 +205,14 @@ impl<'src> Scanner<'src> {
     }
 
     fn number(&mut self) -> Token<'src> {
-        while self.peek().filter(|c| c.is_digit(10)).is_some() {
+        while self.peek().filter(|c| c.is_ascii_digit()).is_some() {
             self.advance();
         }
-        if self.peek() == Some('.') && self.peek_next().filter(|c| c.is_digit(10)).is_some() {
+        if self.peek() == Some('.') && self.peek_next().filter(|c| c.is_ascii_digit()).is_some() {
             // Consume the ".".
             self.advance();
 
-            while self.peek().filter(|c| c.is_digit(10)).is_some() {
+            while self.peek().filter(|c| c.is_ascii_digit()).is_some() {
                 self.advance();
             }
         }
@@ -220,7 +233,18 @@ impl<'src> Scanner<'src> {
     fn identifier_type(&self) -> TokenType {
         match self.source.as_bytes()[self.start] as char {
             'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
+            'b' => self.check_keyword(1, 4, "reak", TokenType::Break),
+            'c' => {
+                if self.current - self.start > 1 {
+                    match self.source.as_bytes()[self.start + 1] as char {
+                        'l' => self.check_keyword(2, 3, "ass", TokenType::Class),
+                        'o' => self.check_keyword(2, 6, "ntinue", TokenType::Continue),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
             'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
             'f' => {
                 if self.current - self.start > 1 {
@@ -272,7 +296,9 @@ impl<'src> Scanner<'src> {
         Token {
             typ,
             name: &self.source[self.start..self.current],
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            span: self.start..self.current,
         }
     }
 
@@ -280,10 +306,18 @@ impl<'src> Scanner<'src> {
         Token {
             typ: TokenType::Error,
             name: message,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            span: self.start..self.current.min(self.source.len()),
         }
     }
 
+    // Called just after consuming a newline.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn is_at_end(&self) -> bool {
         self.source.is_empty() || self.current == self.source.len()
     }
@@ -314,6 +348,9 @@ mod tests {
         let test_cases = vec![
             ("if", TokenType::If),
             ("class", TokenType::Class),
+            ("break", TokenType::Break),
+            ("continue", TokenType::Continue),
+            ("cont", TokenType::Identifier),
             ("false", TokenType::False),
             ("true", TokenType::True),
             ("true_", TokenType::Identifier),
@@ -337,4 +374,27 @@ mod tests {
         assert_eq!(bang.typ, TokenType::Bang);
         assert_eq!(true_.typ, TokenType::True);
     }
+
+    #[test]
+    fn scan_token_positions() {
+        let mut s = Scanner::new("var a =\n  \"x\ny\" + b;");
+        let positions: Vec<_> = std::iter::from_fn(|| {
+            let tok = s.scan_token();
+            (tok.typ != TokenType::Eof).then_some((tok.name, tok.line, tok.column))
+        })
+        .collect();
+
+        assert_eq!(
+            positions,
+            [
+                ("var", 1, 1),
+                ("a", 1, 5),
+                ("=", 1, 7),
+                ("\"x\ny\"", 2, 3),
+                ("+", 3, 4),
+                ("b", 3, 6),
+                (";", 3, 7),
+            ]
+        );
+    }
 }
@@ -1,33 +1,54 @@
+use super::memory::{Heap, ObjRef};
 use super::object::Object;
 use std::ops::Index;
-use std::rc::Rc;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub enum Value {
     Boolean(bool),
     Number(f64),
     Nil,
-    Obj(Rc<Object>),
+    Obj(ObjRef),
 }
 
 impl Value {
-    pub fn as_obj(&self) -> Option<&Object> {
+    pub fn as_obj(&self) -> Option<ObjRef> {
         match *self {
-            Value::Obj(ref obj) => Some(obj.as_ref()),
+            Value::Obj(obj) => Some(obj),
             _ => None,
         }
     }
 
-    pub fn new_string(s: String) -> Value {
-        Value::Obj(Rc::new(Object::String(s)))
+    pub fn string(&self, heap: &Heap) -> Option<String> {
+        match *self {
+            Value::Obj(obj) if heap.get(obj).is_string() => Some(heap.get(obj).as_str().to_owned()),
+            _ => None,
+        }
     }
 
-    pub fn string(&self) -> Option<String> {
-        match &self {
-            Value::Obj(obj) if obj.is_string() => Some(obj.as_str().to_owned()),
-            _ => None,
+    /// The value's type as `type_of` reports it.
+    pub fn type_name(&self, heap: &Heap) -> &'static str {
+        match *self {
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::Nil => "nil",
+            Value::Obj(obj) => match heap.get(obj) {
+                Object::String(_) => "string",
+                Object::Class(_) => "class",
+                Object::Instance(_) => "instance",
+                Object::List(_) => "list",
+                Object::Function(_)
+                | Object::Closure(_)
+                | Object::BoundMethod(_)
+                | Object::Native(_) => "function",
+                Object::Upvalue(_) => unreachable!("upvalues are not values"),
+            },
         }
     }
+
+    /// Formats the value the way `print` shows it.
+    pub fn display<'a>(&self, heap: &'a Heap) -> ValueDisplay<'a> {
+        ValueDisplay { value: *self, heap }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -49,14 +70,45 @@ impl ValueArray {
     }
 }
 
-impl std::fmt::Display for Value {
+pub struct ValueDisplay<'a> {
+    value: Value,
+    heap: &'a Heap,
+}
+
+impl std::fmt::Display for ValueDisplay<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Number(number) => write!(f, "{}", number),
-            Self::Boolean(bool) => write!(f, "{}", bool),
-            Self::Nil => write!(f, "nil"),
-            Self::Obj(obj) => match obj.as_ref() {
+        let heap = self.heap;
+        let function = |obj: ObjRef| heap.get(obj).as_function().unwrap();
+        let closure = |obj: ObjRef| heap.get(obj).as_closure().unwrap();
+
+        match self.value {
+            Value::Number(number) => write!(f, "{}", number),
+            Value::Boolean(bool) => write!(f, "{}", bool),
+            Value::Nil => write!(f, "nil"),
+            Value::Obj(obj) => match heap.get(obj) {
                 Object::String(s) => write!(f, "{}", s),
+                Object::Function(fun) => write!(f, "{}", fun),
+                Object::Closure(c) => write!(f, "{}", function(c.function)),
+                Object::Upvalue(_) => write!(f, "upvalue"),
+                Object::Class(class) => write!(f, "{}", class.name),
+                Object::Instance(instance) => {
+                    let class = heap.get(instance.class).as_class().unwrap();
+                    write!(f, "{} instance", class.name)
+                }
+                Object::BoundMethod(bound) => {
+                    write!(f, "{}", function(closure(bound.method).function))
+                }
+                Object::Native(_) => write!(f, "<native fn>"),
+                Object::List(values) => {
+                    write!(f, "[")?;
+                    for (i, value) in values.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{}", value.display(heap))?;
+                    }
+                    write!(f, "]")
+                }
             },
         }
     }
@@ -0,0 +1,594 @@
+//! Checks a chunk from an untrusted source before the VM runs it, so that
+//! malformed bytecode is reported instead of panicking mid-execution.
+//!
+//! Every function in the chunk's constant pool is verified too. The checks
+//! cover decoding, operand bounds, jump targets, stack depth, a final
+//! `Return` and a line table for every instruction. They don't track the types of values on the stack: an operand
+//! of the wrong type is a runtime error when the instruction runs.
+
+use crate::chunk::{Chunk, OpCode};
+use crate::memory::Heap;
+use crate::object::Function;
+use crate::vm::VM;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{function} at offset {offset}: {kind}")]
+pub struct VerifyError {
+    /// The function containing the bad instruction, as `<fn name>` or `<script>`.
+    pub function: String,
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum VerifyErrorKind {
+    #[error("invalid or truncated instruction")]
+    InvalidInstruction,
+    #[error("constant {0} is out of range")]
+    ConstantOutOfRange(usize),
+    #[error("constant {0} is not a string")]
+    ExpectedString(usize),
+    #[error("constant {0} is not a function")]
+    ExpectedFunction(usize),
+    #[error("local slot {0} is out of range")]
+    LocalOutOfRange(usize),
+    #[error("upvalue {0} is out of range")]
+    UpvalueOutOfRange(usize),
+    #[error("global slot {0} is out of range")]
+    GlobalOutOfRange(usize),
+    #[error("jump to {0} does not land on an instruction")]
+    BadJumpTarget(usize),
+    #[error("loop jumps back past the start of the chunk")]
+    JumpBeforeStart,
+    #[error("stack underflow")]
+    StackUnderflow,
+    #[error("stack depth is {0} on one path and {1} on another")]
+    StackMismatch(usize, usize),
+    #[error("execution can run past the end of the chunk")]
+    MissingReturn,
+    #[error("line table does not start at the first instruction")]
+    MissingLines,
+}
+
+impl Chunk {
+    /// Verifies this chunk as a script to be run by `vm`.
+    pub fn verify(&self, vm: &VM) -> Result<(), VerifyError> {
+        let context = Context {
+            heap: vm.heap(),
+            globals: vm.global_count(),
+        };
+        context.function("<script>".to_owned(), self, 0, 0)
+    }
+}
+
+struct Context<'a> {
+    heap: &'a Heap,
+    globals: usize,
+}
+
+impl Context<'_> {
+    fn function(
+        &self,
+        name: String,
+        chunk: &Chunk,
+        arity: usize,
+        upvalues: usize,
+    ) -> Result<(), VerifyError> {
+        let verifier = Verifier {
+            context: self,
+            name,
+            chunk,
+            upvalues,
+        };
+        let instructions = verifier.decode()?;
+        verifier.stack_depths(&instructions, arity)?;
+
+        for &value in &chunk.constants.values {
+            if let Some(function) = value
+                .as_obj()
+                .and_then(|obj| self.heap.get(obj).as_function())
+            {
+                self.nested(function)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn nested(&self, function: &Function) -> Result<(), VerifyError> {
+        self.function(
+            function.to_string(),
+            &function.chunk,
+            function.arity,
+            function.upvalues.len(),
+        )
+    }
+}
+
+struct Verifier<'a> {
+    context: &'a Context<'a>,
+    name: String,
+    chunk: &'a Chunk,
+    upvalues: usize,
+}
+
+// How an instruction uses the stack: values it needs, values it leaves in
+// their place, and where execution goes next.
+struct Effect {
+    pops: usize,
+    pushes: usize,
+    falls_through: bool,
+    jump: Option<usize>,
+}
+
+impl Verifier<'_> {
+    fn error(&self, offset: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError {
+            function: self.name.clone(),
+            offset,
+            kind,
+        }
+    }
+
+    /// Decodes every instruction, checking the operands that don't depend on
+    /// the stack.
+    fn decode(&self) -> Result<Vec<(usize, OpCode)>, VerifyError> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < self.chunk.code.len() {
+            let (op, next) = self
+                .chunk
+                .read_instruction(offset)
+                .ok_or_else(|| self.error(offset, VerifyErrorKind::InvalidInstruction))?;
+            self.operands(offset, op)?;
+            instructions.push((offset, op));
+            offset = next;
+        }
+
+        match instructions.last() {
+            Some((_, OpCode::Return)) => {}
+            _ => return Err(self.error(offset, VerifyErrorKind::MissingReturn)),
+        }
+        // Runtime errors look up the position of whatever instruction failed.
+        if self.chunk.lines.get(0).is_none() {
+            return Err(self.error(0, VerifyErrorKind::MissingLines));
+        }
+        Ok(instructions)
+    }
+
+    fn operands(&self, offset: usize, op: OpCode) -> Result<(), VerifyError> {
+        let constant = |index: usize| match self.chunk.constants.values.get(index) {
+            Some(&value) => Ok(value),
+            None => Err(self.error(offset, VerifyErrorKind::ConstantOutOfRange(index))),
+        };
+        let string = |index: usize| {
+            let value = constant(index)?;
+            match value.as_obj().map(|obj| self.context.heap.get(obj)) {
+                Some(object) if object.is_string() => Ok(()),
+                _ => Err(self.error(offset, VerifyErrorKind::ExpectedString(index))),
+            }
+        };
+        let global = |slot: usize| {
+            if slot < self.context.globals {
+                Ok(())
+            } else {
+                Err(self.error(offset, VerifyErrorKind::GlobalOutOfRange(slot)))
+            }
+        };
+        let upvalue = |index: usize| {
+            if index < self.upvalues {
+                Ok(())
+            } else {
+                Err(self.error(offset, VerifyErrorKind::UpvalueOutOfRange(index)))
+            }
+        };
+
+        match op {
+            OpCode::Constant(index) => constant(index as usize).map(drop),
+            OpCode::ConstantLong(index) => constant(index as usize).map(drop),
+            OpCode::GetGlobal(slot) | OpCode::DefineGlobal(slot) | OpCode::SetGlobal(slot) => {
+                global(slot as usize)
+            }
+            OpCode::GetGlobalLong(slot)
+            | OpCode::DefineGlobalLong(slot)
+            | OpCode::SetGlobalLong(slot) => global(slot as usize),
+            OpCode::GetUpvalue(index) | OpCode::SetUpvalue(index) => upvalue(index as usize),
+            OpCode::GetProperty(name)
+            | OpCode::SetProperty(name)
+            | OpCode::GetSuper(name)
+            | OpCode::Invoke(name, _)
+            | OpCode::SuperInvoke(name, _)
+            | OpCode::Class(name)
+            | OpCode::Method(name) => string(name as usize),
+            OpCode::GetPropertyLong(name)
+            | OpCode::SetPropertyLong(name)
+            | OpCode::GetSuperLong(name)
+            | OpCode::InvokeLong(name, _)
+            | OpCode::SuperInvokeLong(name, _)
+            | OpCode::ClassLong(name)
+            | OpCode::MethodLong(name) => string(name as usize),
+            OpCode::Closure(index) => self.closure_function(offset, index as usize).map(drop),
+            OpCode::ClosureLong(index) => self.closure_function(offset, index as usize).map(drop),
+            _ => Ok(()),
+        }
+    }
+
+    fn closure_function(&self, offset: usize, index: usize) -> Result<&Function, VerifyError> {
+        let value = *self
+            .chunk
+            .constants
+            .values
+            .get(index)
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::ConstantOutOfRange(index)))?;
+        value
+            .as_obj()
+            .and_then(|obj| self.context.heap.get(obj).as_function())
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::ExpectedFunction(index)))
+    }
+
+    fn effect(&self, offset: usize, op: OpCode, depth: usize) -> Result<Effect, VerifyError> {
+        let next = offset + op.size();
+        let local = |slot: usize| {
+            if slot < depth {
+                Ok(())
+            } else {
+                Err(self.error(offset, VerifyErrorKind::LocalOutOfRange(slot)))
+            }
+        };
+        let (pops, pushes) = match op {
+            OpCode::Constant(_)
+            | OpCode::ConstantLong(_)
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobal(_)
+            | OpCode::GetGlobalLong(_)
+            | OpCode::GetUpvalue(_)
+            | OpCode::Class(_)
+            | OpCode::ClassLong(_) => (0, 1),
+            OpCode::GetLocal(slot) => {
+                local(slot as usize)?;
+                (0, 1)
+            }
+            OpCode::GetLocalLong(slot) => {
+                local(slot as usize)?;
+                (0, 1)
+            }
+            OpCode::SetLocal(slot) => {
+                local(slot as usize)?;
+                (1, 1)
+            }
+            OpCode::SetLocalLong(slot) => {
+                local(slot as usize)?;
+                (1, 1)
+            }
+            OpCode::Closure(index) => {
+                self.closure_upvalues(offset, index as usize, depth)?;
+                (0, 1)
+            }
+            OpCode::ClosureLong(index) => {
+                self.closure_upvalues(offset, index as usize, depth)?;
+                (0, 1)
+            }
+            OpCode::Pop
+            | OpCode::DefineGlobal(_)
+            | OpCode::DefineGlobalLong(_)
+            | OpCode::Print
+            | OpCode::CloseUpvalue => (1, 0),
+            OpCode::SetGlobal(_)
+            | OpCode::SetGlobalLong(_)
+            | OpCode::SetUpvalue(_)
+            | OpCode::GetProperty(_)
+            | OpCode::GetPropertyLong(_)
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::JumpIfFalse(_) => (1, 1),
+            OpCode::SetProperty(_)
+            | OpCode::SetPropertyLong(_)
+            | OpCode::GetSuper(_)
+            | OpCode::GetSuperLong(_)
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Inherit
+            | OpCode::Method(_)
+            | OpCode::MethodLong(_) => (2, 1),
+            OpCode::Call(arg_count)
+            | OpCode::Invoke(_, arg_count)
+            | OpCode::InvokeLong(_, arg_count) => (arg_count as usize + 1, 1),
+            // The receiver, the arguments and the superclass.
+            OpCode::SuperInvoke(_, arg_count) | OpCode::SuperInvokeLong(_, arg_count) => {
+                (arg_count as usize + 2, 1)
+            }
+            OpCode::Jump(_) | OpCode::Loop(_) => (0, 0),
+            OpCode::Return => (1, 0),
+        };
+
+        let jump = match op {
+            OpCode::Jump(jump) | OpCode::JumpIfFalse(jump) => Some(next + jump as usize),
+            OpCode::Loop(jump) => Some(
+                next.checked_sub(jump as usize)
+                    .ok_or_else(|| self.error(offset, VerifyErrorKind::JumpBeforeStart))?,
+            ),
+            _ => None,
+        };
+        let falls_through = !matches!(op, OpCode::Jump(_) | OpCode::Loop(_) | OpCode::Return);
+        Ok(Effect {
+            pops,
+            pushes,
+            falls_through,
+            jump,
+        })
+    }
+
+    fn closure_upvalues(
+        &self,
+        offset: usize,
+        index: usize,
+        depth: usize,
+    ) -> Result<(), VerifyError> {
+        let function = self.closure_function(offset, index)?;
+        for upvalue in &function.upvalues {
+            let index = upvalue.index as usize;
+            if upvalue.is_local && index >= depth {
+                return Err(self.error(offset, VerifyErrorKind::LocalOutOfRange(index)));
+            }
+            if !upvalue.is_local && index >= self.upvalues {
+                return Err(self.error(offset, VerifyErrorKind::UpvalueOutOfRange(index)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Follows every path through the code, checking that each instruction
+    /// is reached with the same stack depth however execution got there.
+    fn stack_depths(
+        &self,
+        instructions: &[(usize, OpCode)],
+        arity: usize,
+    ) -> Result<(), VerifyError> {
+        let len = self.chunk.code.len();
+        // Depth on entry to the instruction at each offset; `None` for
+        // offsets that aren't the start of an instruction.
+        let mut depths: Vec<Option<Option<usize>>> = vec![None; len];
+        let mut ops = vec![None; len];
+        for &(offset, op) in instructions {
+            depths[offset] = Some(None);
+            ops[offset] = Some(op);
+        }
+
+        // Slot zero holds the callee, followed by the arguments.
+        let mut pending = vec![(0, arity + 1)];
+        depths[0] = Some(Some(arity + 1));
+        while let Some((offset, depth)) = pending.pop() {
+            let op = ops[offset].unwrap();
+            let effect = self.effect(offset, op, depth)?;
+            if depth < effect.pops {
+                return Err(self.error(offset, VerifyErrorKind::StackUnderflow));
+            }
+            let after = depth - effect.pops + effect.pushes;
+
+            // Only `Return` may be last, so falling through always reaches
+            // another instruction.
+            let fall = Some(offset + op.size()).filter(|_| effect.falls_through);
+            for target in fall.into_iter().chain(effect.jump) {
+                match depths.get_mut(target) {
+                    Some(Some(seen @ None)) => {
+                        *seen = Some(after);
+                        pending.push((target, after));
+                    }
+                    Some(Some(Some(seen))) if *seen != after => {
+                        let kind = VerifyErrorKind::StackMismatch(*seen, after);
+                        return Err(self.error(offset, kind));
+                    }
+                    Some(Some(Some(_))) => {}
+                    Some(None) | None => {
+                        let kind = VerifyErrorKind::BadJumpTarget(target);
+                        return Err(self.error(offset, kind));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Position;
+    use crate::compiler::Compiler;
+    use crate::value::Value;
+    use crate::vm::InterpretError;
+
+    fn chunk(ops: &[OpCode]) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::Number(1.0));
+        for &op in ops {
+            chunk.write_chunk(op, Position { line: 1, column: 1 });
+        }
+        chunk
+    }
+
+    fn verify(chunk: &Chunk) -> Result<(), (usize, VerifyErrorKind)> {
+        chunk
+            .verify(&VM::new())
+            .map_err(|error| (error.offset, error.kind))
+    }
+
+    #[test]
+    fn compiled_code_verifies() {
+        let mut long = String::from("var sum = 0;");
+        for i in 0..300 {
+            long.push_str(&format!("sum = sum + {};", i));
+        }
+        let sources = [
+            "var a = 1; if (a and !false or nil) print a; else print -a;",
+            "for (var i = 0; i < 3; i = i + 1) { var x = i; if (x == 1) continue; \
+             while (true) { var y = x; fun f() { return y; } break; } }",
+            "fun make() { var n = 0; fun inc() { n = n + 1; return n; } return inc; } \
+             var c = make(); c();",
+            "class A { init(x) { this.x = x; } get() { return this.x; } } \
+             class B < A { get() { return super.get() + 1; } raw() { return super.get; } } \
+             var b = B(1); b.y = b.get(); b.raw()();",
+            "{ class Local < A {} var l = Local(2); }",
+            &long,
+        ];
+
+        let mut vm = VM::new();
+        vm.interpret("class A { init(x) {} }").unwrap();
+        for source in sources.iter() {
+            let function = Compiler::new(&mut vm, source).compile().unwrap();
+            assert_eq!(function.chunk.verify(&vm), Ok(()), "{}", source);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_operands() {
+        use VerifyErrorKind::*;
+
+        assert_eq!(
+            verify(&chunk(&[OpCode::Constant(1), OpCode::Return])),
+            Err((0, ConstantOutOfRange(1)))
+        );
+        assert_eq!(
+            verify(&chunk(&[OpCode::GetProperty(0), OpCode::Return])),
+            Err((0, ExpectedString(0)))
+        );
+        assert_eq!(
+            verify(&chunk(&[OpCode::Closure(0), OpCode::Return])),
+            Err((0, ExpectedFunction(0)))
+        );
+        assert_eq!(
+            verify(&chunk(&[OpCode::GetGlobalLong(5000), OpCode::Return])),
+            Err((0, GlobalOutOfRange(5000)))
+        );
+        assert_eq!(
+            verify(&chunk(&[OpCode::GetUpvalue(0), OpCode::Return])),
+            Err((0, UpvalueOutOfRange(0)))
+        );
+        assert_eq!(
+            verify(&chunk(&[OpCode::Nil, OpCode::GetLocal(2), OpCode::Return])),
+            Err((1, LocalOutOfRange(2)))
+        );
+
+        let mut invalid = chunk(&[OpCode::Nil, OpCode::Return]);
+        invalid.code[1] = 0xff;
+        assert_eq!(verify(&invalid), Err((1, InvalidInstruction)));
+
+        let mut no_lines = chunk(&[]);
+        no_lines.code = chunk(&[OpCode::Nil, OpCode::Return]).code;
+        assert_eq!(verify(&no_lines), Err((0, MissingLines)));
+
+        let mut late_lines = chunk(&[]);
+        late_lines.code = chunk(&[OpCode::Nil]).code;
+        late_lines.write_chunk(OpCode::Return, Position { line: 1, column: 1 });
+        assert_eq!(verify(&late_lines), Err((0, MissingLines)));
+    }
+
+    #[test]
+    fn rejects_bad_control_flow() {
+        use VerifyErrorKind::*;
+
+        assert_eq!(verify(&chunk(&[])), Err((0, MissingReturn)));
+        assert_eq!(verify(&chunk(&[OpCode::Nil])), Err((1, MissingReturn)));
+        // Lands on the operand of `Constant`.
+        assert_eq!(
+            verify(&chunk(&[
+                OpCode::Jump(1),
+                OpCode::Constant(0),
+                OpCode::Return
+            ])),
+            Err((0, BadJumpTarget(4)))
+        );
+        assert_eq!(
+            verify(&chunk(&[OpCode::Loop(9), OpCode::Return])),
+            Err((0, JumpBeforeStart))
+        );
+        // Jumping over the final `Return` runs off the end.
+        assert_eq!(
+            verify(&chunk(&[
+                OpCode::Nil,
+                OpCode::JumpIfFalse(1),
+                OpCode::Return
+            ])),
+            Err((1, BadJumpTarget(5)))
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_stack() {
+        use VerifyErrorKind::*;
+
+        assert_eq!(
+            verify(&chunk(&[OpCode::Pop, OpCode::Add, OpCode::Return])),
+            Err((1, StackUnderflow))
+        );
+        // The fallthrough path pushes one more value than the jump skips.
+        assert_eq!(
+            verify(&chunk(&[
+                OpCode::True,
+                OpCode::JumpIfFalse(1),
+                OpCode::Nil,
+                OpCode::Return,
+            ])),
+            Err((4, StackMismatch(2, 3)))
+        );
+    }
+
+    #[test]
+    fn interpret_chunk_refuses_invalid_chunks() {
+        let mut vm = VM::new();
+        let result = vm.interpret_chunk(chunk(&[OpCode::Constant(3), OpCode::Return]));
+
+        assert!(matches!(
+            result,
+            Err(InterpretError::InvalidChunk(VerifyError {
+                offset: 0,
+                kind: VerifyErrorKind::ConstantOutOfRange(3),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn interpret_chunk_reports_wrongly_typed_operands() {
+        let cases = [
+            (
+                &[OpCode::Class(1), OpCode::Nil, OpCode::Method(1)][..],
+                "Method must be a function.",
+            ),
+            (
+                &[OpCode::Class(1), OpCode::Nil, OpCode::Inherit],
+                "Subclass must be a class.",
+            ),
+            (
+                &[OpCode::Nil, OpCode::Nil, OpCode::GetSuper(1)],
+                "Superclass must be a class.",
+            ),
+            (
+                &[OpCode::Nil, OpCode::Nil, OpCode::SuperInvoke(1, 0)],
+                "Superclass must be a class.",
+            ),
+        ];
+
+        let mut vm = VM::new();
+        let name = vm.new_string("A");
+        for (ops, message) in cases {
+            let mut chunk = chunk(ops);
+            chunk.add_constant(name.value());
+            for op in [OpCode::Pop, OpCode::Nil, OpCode::Return] {
+                chunk.write_chunk(op, Position { line: 1, column: 1 });
+            }
+            assert_eq!(chunk.verify(&vm), Ok(()), "{:?}", ops);
+
+            match vm.interpret_chunk(chunk) {
+                Err(InterpretError::RuntimeError(err)) => assert_eq!(err.message, message),
+                other => panic!("{:?}: expected a runtime error, got {:?}", ops, other),
+            }
+        }
+    }
+}
@@ -1,122 +1,534 @@
-use std::collections::{HashMap, HashSet};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::{Rc, Weak};
 
 use crate::chunk::{disassemble_instruction, Chunk, OpCode};
-use crate::common::DEBUG_TRACE_EXECUTION;
+use crate::common::VmOptions;
 use crate::compiler::Compiler;
+use crate::convert::{IntoValue, NativeFunction};
+use crate::diagnostic::Diagnostic;
+use crate::globals::Globals;
+use crate::memory::{Heap, ObjRef};
+use crate::native::define_natives;
 use crate::object::*;
 use crate::value::Value;
+use crate::verify::VerifyError;
+
+const FRAMES_MAX: usize = 64;
 
 pub struct VM {
-    chunk: Chunk,
-    ip: usize,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
-    globals: HashMap<String, Value>,
-    strings: HashSet<String>, // intern
+    open_upvalues: Vec<ObjRef>, // sorted by stack slot
+    globals: Globals,
+    strings: HashMap<String, ObjRef>, // intern, weak
+    init_string: ObjRef,
+    heap: Heap,
+    compiler_roots: Vec<ObjRef>,
+    // Objects held by live `Rooted` handles.
+    host_roots: RefCell<Vec<(Weak<()>, ObjRef)>>,
+    output: Box<dyn Write>,       // what `print` writes
+    error_output: Box<dyn Write>, // compile and runtime errors
+    trace_output: Box<dyn Write>, // disassembly and execution traces
+    options: VmOptions,
+    fuel: Option<u64>,        // instructions left to run; `None` is unlimited
+    suspended: Option<usize>, // frame depth of a script that ran out of fuel
+    // Frame and stack heights when the innermost `call` began; a runtime
+    // error unwinds only down to them.
+    call_base: Option<(usize, usize)>,
+}
+
+struct CallFrame {
+    closure: ObjRef,
+    function: ObjRef,
+    ip: usize,
+    slots: usize,
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum InterpretError {
     #[error("Compile error")]
-    CompileError,
+    CompileError(Vec<Diagnostic>),
     #[error("Runtime error")]
-    RuntimeError,
+    RuntimeError(RuntimeError),
+    #[error("Invalid chunk: {0}")]
+    InvalidChunk(#[from] VerifyError),
+    /// The script used up its instruction budget; see [`VM::resume`].
+    #[error("Out of fuel")]
+    OutOfFuel,
+}
+
+/// An error raised while running a script.
+///
+/// Natives build one with just a message; the VM fills in the position of the
+/// failing instruction and the call stack before handing it to the embedder.
+/// `Display` prints the message followed by one `[line L:C] in f()` line per
+/// frame, innermost first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub trace: Vec<StackFrame>,
+}
+
+/// One active call at the point a runtime error was raised.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    /// The function's name, or `None` for the top-level script.
+    pub function: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> Self {
+        RuntimeError {
+            message: message.into(),
+            line: 0,
+            column: 0,
+            trace: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}:{}] in ", self.line, self.column)?;
+        match &self.function {
+            Some(name) => write!(f, "{}()", name),
+            None => write!(f, "script"),
+        }
+    }
+}
+
+/// A value handed to the host. The collector keeps it alive until the last
+/// clone of the handle is dropped, so the host can hold on to it across
+/// allocations and calls.
+#[derive(Debug, Clone)]
+pub struct Rooted {
+    value: Value,
+    // The VM watches this through a `Weak`; `None` for values that aren't
+    // objects and so need no rooting.
+    _root: Option<Rc<()>>,
+}
+
+impl Rooted {
+    pub fn value(&self) -> Value {
+        self.value
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
-        VM {
-            chunk: Chunk::new(),
-            ip: 0,
+        VM::with_options(VmOptions::default())
+    }
+
+    pub fn with_options(options: VmOptions) -> Self {
+        let mut heap = Heap::new();
+        heap.set_stress(options.stress_gc);
+        let init_string = heap.alloc(Object::String("init".to_owned()));
+        let mut strings = HashMap::new();
+        strings.insert("init".to_owned(), init_string);
+
+        let mut vm = VM {
+            frames: Vec::new(),
             stack: Vec::new(),
-            globals: HashMap::new(),
-            strings: HashSet::new(),
-        }
+            open_upvalues: Vec::new(),
+            globals: Globals::new(),
+            strings,
+            init_string,
+            heap,
+            compiler_roots: Vec::new(),
+            host_roots: RefCell::new(Vec::new()),
+            output: Box::new(io::stdout()),
+            error_output: Box::new(io::sink()),
+            trace_output: Box::new(io::stdout()),
+            options,
+            fuel: None,
+            suspended: None,
+            call_base: None,
+        };
+        define_natives(&mut vm);
+        vm
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn options(&self) -> VmOptions {
+        self.options
+    }
+
+    /// Run a full collection before every allocation.
+    pub fn set_stress_gc(&mut self, stress: bool) {
+        self.options.stress_gc = stress;
+        self.heap.set_stress(stress);
+    }
+
+    /// Where `print` writes. Defaults to stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Where errors from [`VM::interpret`], [`VM::interpret_chunk`] and
+    /// [`VM::compile`] are reported as they are returned. Defaults to
+    /// discarding them, since they are returned anyway.
+    pub fn set_error_output(&mut self, output: Box<dyn Write>) {
+        self.error_output = output;
+    }
+
+    /// Where disassembly and execution traces go. Defaults to stdout.
+    pub fn set_trace_output(&mut self, output: Box<dyn Write>) {
+        self.trace_output = output;
+    }
+
+    /// Installs a Rust function as a global callable from scripts.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&mut VM, &[Value]) -> Result<Value, RuntimeError> + 'static,
+    ) {
+        let native = Native {
+            name: name.to_owned(),
+            arity,
+            function: Rc::new(function),
+        };
+        let name = self.intern(name);
+        let slot = self.globals.slot(name);
+        let native = self.alloc(Object::Native(native));
+        self.globals.define(slot, Value::Obj(native));
+    }
+
+    /// Installs a Rust closure as a native, converting each argument with
+    /// [`FromValue`](crate::convert::FromValue) and the result with [`IntoValue`]. Arguments of the wrong
+    /// type raise a runtime error naming the expected type.
+    pub fn define_typed_native<Args>(&mut self, name: &str, function: impl NativeFunction<Args>) {
+        let arity = function.arity();
+        let native_name = name.to_owned();
+        self.define_native(name, arity, move |vm, args| {
+            function.invoke(vm, &native_name, args)
+        });
+    }
+
+    /// Slot of the global variable `name`, reserved if not seen before.
+    pub(crate) fn global_slot(&mut self, name: ObjRef) -> usize {
+        self.globals.slot(name)
+    }
+
+    pub(crate) fn global_slot_for(&mut self, name: &str) -> usize {
+        let name = self.intern(name);
+        self.global_slot(name)
+    }
+
+    pub(crate) fn global_count(&self) -> usize {
+        self.globals.names().len()
+    }
+
+    /// Names of the global slots, in slot order.
+    pub(crate) fn global_names(&self) -> impl Iterator<Item = &str> {
+        self.globals
+            .names()
+            .iter()
+            .map(move |&name| self.heap.get(name).as_str())
+    }
+
+    /// Keeps `value` alive for as long as the returned handle.
+    pub fn root(&self, value: Value) -> Rooted {
+        let root = value.as_obj().map(|obj| {
+            let root = Rc::new(());
+            let mut roots = self.host_roots.borrow_mut();
+            // Forget dropped handles before growing, in case nothing collects.
+            if roots.len() == roots.capacity() {
+                roots.retain(|(handle, _)| handle.strong_count() > 0);
+            }
+            roots.push((Rc::downgrade(&root), obj));
+            root
+        });
+        Rooted { value, _root: root }
     }
 
-    pub fn new_string(&mut self, s: impl Into<String>) -> Value {
+    /// Returns the interned string object for `s`, allocating it on first use.
+    pub fn new_string(&mut self, s: impl Into<String>) -> Rooted {
+        let string = self.intern(s);
+        self.root(Value::Obj(string))
+    }
+
+    // The interned string for `s`, which nothing keeps alive yet.
+    pub(crate) fn intern(&mut self, s: impl Into<String>) -> ObjRef {
         let s = s.into();
-        self.strings.insert(s.clone());
-        Value::new_string(s)
+        if let Some(&interned) = self.strings.get(&s) {
+            return interned;
+        }
+        let string = self.alloc(Object::String(s.clone()));
+        self.strings.insert(s, string);
+        string
+    }
+
+    /// Allocates a list of `items`, converted with [`IntoValue`].
+    pub fn new_list<T: IntoValue>(&mut self, items: impl IntoIterator<Item = T>) -> Rooted {
+        let items: Vec<Rooted> = items
+            .into_iter()
+            .map(|item| item.into_value(self))
+            .collect();
+        let list = self.alloc(Object::List(items.iter().map(Rooted::value).collect()));
+        self.root(Value::Obj(list))
     }
 
     pub fn interpret(&mut self, source: &str) -> anyhow::Result<(), InterpretError> {
-        let mut chunk = Chunk::new();
-        let mut compiler = Compiler::new(self, source, &mut chunk);
-        compiler
-            .compile()
-            .map_err(|_err| InterpretError::CompileError)?;
+        let result = self
+            .compile_function(source)
+            .and_then(|function| self.run_script(function));
+        self.report(result)
+    }
+
+    /// Compiles `source` into a script that can be run any number of times
+    /// with [`VM::call`], without compiling it again.
+    pub fn compile(&mut self, source: &str) -> Result<Rooted, InterpretError> {
+        let result = self.compile_function(source);
+        let function = self.report(result)?;
+        Ok(self.new_script(function))
+    }
+
+    // Wraps a top-level function in a closure the host can hold and call.
+    pub(crate) fn new_script(&mut self, function: Function) -> Rooted {
+        let function = self.alloc(Object::Function(function));
+        let closure = self.alloc(Object::Closure(Closure::new(function)));
+        self.root(Value::Obj(closure))
+    }
+
+    fn compile_function(&mut self, source: &str) -> Result<Function, InterpretError> {
+        let mut compiler = Compiler::new(self, source);
+        let result = compiler.compile();
+        self.compiler_roots.clear();
+        result.map_err(InterpretError::CompileError)
+    }
+
+    /// Runs a script chunk built in memory, without compiling. The chunk is
+    /// verified first, since it may not have come from our compiler. Its object
+    /// constants must be kept alive by the caller, with [`Rooted`] handles;
+    /// [`Chunk::read_from`] loads files as scripts that are kept alive.
+    pub fn interpret_chunk(&mut self, chunk: Chunk) -> Result<(), InterpretError> {
+        let verified = chunk.verify(self);
+        self.compiler_roots.clear();
+        if let Err(err) = verified {
+            return self.report(Err(err.into()));
+        }
+        let mut function = Function::new(None);
+        function.chunk = chunk;
+        let result = self.run_script(function);
+        self.report(result)
+    }
+
+    // Writes the error in `result`, if any, to the error output.
+    fn report<T>(&mut self, result: Result<T, InterpretError>) -> Result<T, InterpretError> {
+        let out = &mut self.error_output;
+        let _ = match &result {
+            Err(InterpretError::CompileError(diagnostics)) => diagnostics
+                .iter()
+                .try_for_each(|diagnostic| writeln!(out, "{}", diagnostic)),
+            Err(InterpretError::RuntimeError(err)) => writeln!(out, "{}", err),
+            Err(err @ InterpretError::InvalidChunk(_)) => writeln!(out, "{}", err),
+            // Not an error in the program; the host decides whether to resume.
+            Err(InterpretError::OutOfFuel) | Ok(_) => Ok(()),
+        };
+        result
+    }
+
+    /// Writes `chunk`'s disassembly to the trace output.
+    pub(crate) fn trace_chunk(&mut self, chunk: &Chunk, name: &str) {
+        let _ = chunk.disassemble(name, &self.heap, &mut self.trace_output);
+    }
+
+    /// Writes one line to the trace output.
+    pub(crate) fn trace_line(&mut self, line: fmt::Arguments<'_>) {
+        let _ = writeln!(self.trace_output, "{}", line);
+    }
+
+    fn run_script(&mut self, function: Function) -> Result<(), InterpretError> {
+        if self.suspended.is_some() {
+            self.reset_stack();
+        }
+        let depth = self.frames.len();
+        let function = self.alloc(Object::Function(function));
+        self.push(Value::Obj(function));
+        let closure = self.alloc(Object::Closure(Closure::new(function)));
+        self.pop();
+        self.push(Value::Obj(closure));
+        self.call_closure(closure, 0)?;
 
-        let chunk = chunk;
-        self.chunk = chunk;
-        self.ip = 0;
+        self.finish_script(depth)
+    }
+
+    fn finish_script(&mut self, depth: usize) -> Result<(), InterpretError> {
+        match self.run(depth) {
+            Ok(()) => {
+                self.pop();
+                Ok(())
+            }
+            Err(InterpretError::OutOfFuel) => {
+                self.suspended = Some(depth);
+                Err(InterpretError::OutOfFuel)
+            }
+            Err(err) => Err(err),
+        }
+    }
 
-        self.run()
+    /// Limits how many more instructions the VM may run; `None` lifts the
+    /// limit. A script that reaches the limit stops with
+    /// [`InterpretError::OutOfFuel`] and can be continued with [`VM::resume`].
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
     }
 
-    pub fn run(&mut self) -> Result<(), InterpretError> {
-        dbg!(&self.chunk);
+    /// Instructions left before the VM runs out of fuel, if limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
 
+    /// Continues the script that last ran out of fuel with `fuel` more
+    /// instructions. Does nothing if no script is suspended. Starting another
+    /// script with [`VM::interpret`] or [`VM::interpret_chunk`] abandons a
+    /// suspended one.
+    pub fn resume(&mut self, fuel: u64) -> Result<(), InterpretError> {
+        self.fuel = Some(fuel);
+        let result = match self.suspended.take() {
+            Some(depth) => self.finish_script(depth),
+            None => Ok(()),
+        };
+        self.report(result)
+    }
+
+    /// Calls a Lox function, method, class or native from Rust and returns its
+    /// result. Scripts returned by [`VM::compile`] are called with no
+    /// arguments.
+    ///
+    /// Running out of fuel abandons the call with an "Out of fuel." error;
+    /// only scripts can be resumed. A call made while a script is suspended
+    /// leaves that script to be resumed, whether or not the call fails.
+    pub fn call(&mut self, callee: Value, args: &[Value]) -> Result<Rooted, RuntimeError> {
+        let depth = self.frames.len();
+        let outer_base = self.call_base.replace((depth, self.stack.len()));
+        self.push(callee);
+        for &arg in args {
+            self.push(arg);
+        }
+        let result = self
+            .call_value(callee, args.len())
+            .and_then(|()| {
+                if self.frames.len() > depth {
+                    self.run(depth)?;
+                }
+                let result = self.pop().expect("empty stack");
+                Ok(self.root(result))
+            })
+            .map_err(|err| match err {
+                InterpretError::RuntimeError(err) => err,
+                InterpretError::OutOfFuel => self.capture_error("Out of fuel."),
+                err => RuntimeError::new(err.to_string()),
+            });
+        self.call_base = outer_base;
+        result
+    }
+
+    /// Value of the global variable `name`, if it has been defined.
+    pub fn get_global(&self, name: &str) -> Option<Rooted> {
+        let name = self.strings.get(name)?;
+        self.globals.lookup(*name).map(|value| self.root(value))
+    }
+
+    /// Defines or assigns the global variable `name`.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        // Keep `value` reachable while the name is allocated.
+        self.push(value);
+        let slot = self.global_slot_for(name);
+        self.globals.define(slot, value);
+        self.pop();
+    }
+
+    // Runs until the frame at `depth` returns, leaving its result on the stack.
+    fn run(&mut self, depth: usize) -> Result<(), InterpretError> {
         loop {
-            if DEBUG_TRACE_EXECUTION {
-                disassemble_instruction(&self.chunk, self.ip);
+            if let Some(fuel) = &mut self.fuel {
+                if *fuel == 0 {
+                    return Err(InterpretError::OutOfFuel);
+                }
+                *fuel -= 1;
             }
 
-            let op = self.chunk.code[self.ip];
+            if self.options.trace_execution {
+                let frame = self.frames.last().expect("no call frame");
+                let chunk = &self.heap.get(frame.function).as_function().unwrap().chunk;
+                let _ =
+                    disassemble_instruction(chunk, frame.ip, &self.heap, &mut self.trace_output);
+            }
+
+            let op = self.read_op();
             match op {
-                OpCode::Constant(idx) => {
-                    let constant = self.read_const(idx as usize);
-                    self.push(constant);
-                }
+                OpCode::Constant(idx) => self.push(self.read_const(idx as usize)),
+                OpCode::ConstantLong(idx) => self.push(self.read_const(idx as usize)),
                 OpCode::Nil => self.push(Value::Nil),
                 OpCode::True => self.push(Value::Boolean(true)),
                 OpCode::False => self.push(Value::Boolean(false)),
                 OpCode::Pop => {
                     self.pop();
                 }
-                OpCode::GetLocal(name_idx) => {
-                    self.push(self.stack[name_idx as usize].clone());
-                }
-                OpCode::GetGlobal(name_idx) => {
-                    let k = self.read_const(name_idx as usize);
-                    let k = k.string().unwrap();
-                    match self.globals.get(&k).cloned() {
-                        Some(v) => {
-                            self.push(v.clone());
-                        }
-                        None => {
-                            self.runtime_error(&format!("Undefined variable '{}'.", &k));
-                            return Err(InterpretError::RuntimeError);
-                        }
-                    }
-                }
-                OpCode::DefineGlobal(name_idx) => {
-                    let k = self.read_const(name_idx as usize);
-                    let k = k.string().unwrap();
-                    let v = self.peek(0).unwrap();
-                    self.globals.insert(k, v);
-                    self.pop();
-                }
-                OpCode::SetLocal(name_idx) => {
-                    self.stack[name_idx as usize] = self.peek(0).unwrap();
+                OpCode::GetLocal(slot) => self.get_local(slot as usize),
+                OpCode::GetLocalLong(slot) => self.get_local(slot as usize),
+                OpCode::GetGlobal(slot) => self.get_global_slot(slot as usize)?,
+                OpCode::GetGlobalLong(slot) => self.get_global_slot(slot as usize)?,
+                OpCode::DefineGlobal(slot) => self.define_global_slot(slot as usize),
+                OpCode::DefineGlobalLong(slot) => self.define_global_slot(slot as usize),
+                OpCode::SetLocal(slot) => self.set_local(slot as usize),
+                OpCode::SetLocalLong(slot) => self.set_local(slot as usize),
+                OpCode::SetGlobal(slot) => self.set_global_slot(slot as usize)?,
+                OpCode::SetGlobalLong(slot) => self.set_global_slot(slot as usize)?,
+                OpCode::GetUpvalue(slot) => {
+                    let upvalue = self.closure().upvalues[slot as usize];
+                    let value = match self.heap.get(upvalue) {
+                        Object::Upvalue(Upvalue::Open(slot)) => self.stack[*slot],
+                        Object::Upvalue(Upvalue::Closed(value)) => *value,
+                        _ => unreachable!("not an upvalue"),
+                    };
+                    self.push(value);
                 }
-                OpCode::SetGlobal(name_idx) => {
-                    let k = self.read_const(name_idx as usize);
-                    let k = k.string().unwrap();
-                    let v = self.peek(0).unwrap();
-                    if let Some(var) = self.globals.get_mut(&k) {
-                        *var = v;
-                    } else {
-                        self.runtime_error(&format!("Undefined variable '{}'.", &k));
-                        return Err(InterpretError::RuntimeError);
+                OpCode::SetUpvalue(slot) => {
+                    let upvalue = self.closure().upvalues[slot as usize];
+                    let value = self.peek(0).unwrap();
+                    match self.heap.get_mut(upvalue).as_upvalue_mut().unwrap() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
                     }
                 }
+                OpCode::GetProperty(name_idx) => self.get_property(name_idx as usize)?,
+                OpCode::GetPropertyLong(name_idx) => self.get_property(name_idx as usize)?,
+                OpCode::SetProperty(name_idx) => self.set_property(name_idx as usize)?,
+                OpCode::SetPropertyLong(name_idx) => self.set_property(name_idx as usize)?,
+                OpCode::GetSuper(name_idx) => self.get_super(name_idx as usize)?,
+                OpCode::GetSuperLong(name_idx) => self.get_super(name_idx as usize)?,
                 OpCode::Equal => {
                     let a = self.pop().expect("empty stack");
                     let b = self.pop().expect("empty stack");
                     self.push(Value::Boolean(values_equal(a, b)));
                 }
                 OpCode::Add => {
-                    let b = self.peek(1).expect("empty stack");
+                    let b = self.peek(0).expect("empty stack");
                     let a = self.peek(1).expect("empty stack");
 
                     match (a, b) {
@@ -126,21 +538,19 @@ impl VM {
                         (Value::Obj(_), Value::Obj(_)) => {
                             let b = self.pop().expect("empty stack");
                             let a = self.pop().expect("empty stack");
-                            match (a.as_obj(), b.as_obj()) {
-                                (Some(Object::String(str_a)), Some(Object::String(str_b))) => {
-                                    let new = str_a.to_owned() + str_b;
-                                    let string = self.new_string(new);
-                                    self.push(string);
+                            match (a.string(&self.heap), b.string(&self.heap)) {
+                                (Some(str_a), Some(str_b)) => {
+                                    let new = str_a + &str_b;
+                                    let string = self.intern(new);
+                                    self.push(Value::Obj(string));
                                 }
                                 _ => {
-                                    self.runtime_error("Operands must be strings.");
-                                    return Err(InterpretError::RuntimeError);
+                                    return Err(self.runtime_error("Operands must be strings."));
                                 }
                             }
                         }
                         _ => {
-                            self.runtime_error("Operands must be numbers.");
-                            return Err(InterpretError::RuntimeError);
+                            return Err(self.runtime_error("Operands must be numbers."));
                         }
                     }
                 }
@@ -158,33 +568,411 @@ impl VM {
                     let v = match self.pop().expect("empty stack") {
                         Value::Number(number) => number,
                         _ => {
-                            self.runtime_error("Operand must be a number.");
-                            return Err(InterpretError::RuntimeError);
+                            return Err(self.runtime_error("Operand must be a number."));
                         }
                     };
                     self.push(Value::Number(-v));
                 }
                 OpCode::Print => {
                     let v = self.pop().unwrap();
-                    println!("{}\n", v);
+                    if let Err(err) = writeln!(self.output, "{}", v.display(&self.heap)) {
+                        return Err(self.runtime_error(format!("Could not print: {}.", err)));
+                    }
                 }
                 OpCode::Jump(offset) => {
-                    self.ip += offset as usize;
+                    self.frame_mut().ip += offset as usize;
                 }
                 OpCode::JumpIfFalse(offset) => {
                     if is_falsey(self.peek(0).unwrap()) {
-                        self.ip += offset as usize;
+                        self.frame_mut().ip += offset as usize;
                     }
                 }
+                OpCode::Loop(offset) => {
+                    self.frame_mut().ip -= offset as usize;
+                }
+                OpCode::Call(arg_count) => {
+                    let callee = self.peek(arg_count as usize).expect("empty stack");
+                    self.call_value(callee, arg_count as usize)?;
+                }
+                OpCode::Closure(idx) => self.new_closure(idx as usize)?,
+                OpCode::ClosureLong(idx) => self.new_closure(idx as usize)?,
+                OpCode::Invoke(name_idx, arg_count) => {
+                    let name = self.read_string(name_idx as usize);
+                    self.invoke(name, arg_count as usize)?;
+                }
+                OpCode::InvokeLong(name_idx, arg_count) => {
+                    let name = self.read_string(name_idx as usize);
+                    self.invoke(name, arg_count as usize)?;
+                }
+                OpCode::SuperInvoke(name_idx, arg_count) => {
+                    self.super_invoke(name_idx as usize, arg_count as usize)?
+                }
+                OpCode::SuperInvokeLong(name_idx, arg_count) => {
+                    self.super_invoke(name_idx as usize, arg_count as usize)?
+                }
+                OpCode::Class(name_idx) => self.new_class(name_idx as usize),
+                OpCode::ClassLong(name_idx) => self.new_class(name_idx as usize),
+                OpCode::Inherit => {
+                    let superclass = match self.peek(1).unwrap() {
+                        Value::Obj(obj) if self.heap.get(obj).as_class().is_some() => obj,
+                        _ => {
+                            return Err(self.runtime_error("Superclass must be a class."));
+                        }
+                    };
+                    let subclass = match self.peek(0).unwrap() {
+                        Value::Obj(obj) if self.heap.get(obj).as_class().is_some() => obj,
+                        _ => {
+                            return Err(self.runtime_error("Subclass must be a class."));
+                        }
+                    };
+                    let methods = self
+                        .heap
+                        .get(superclass)
+                        .as_class()
+                        .unwrap()
+                        .methods
+                        .clone();
+                    self.heap
+                        .get_mut(subclass)
+                        .as_class_mut()
+                        .unwrap()
+                        .methods
+                        .extend(methods);
+                    self.pop(); // Subclass.
+                }
+                OpCode::Method(name_idx) => {
+                    let name = self.read_string(name_idx as usize);
+                    self.define_method(name)?;
+                }
+                OpCode::MethodLong(name_idx) => {
+                    let name = self.read_string(name_idx as usize);
+                    self.define_method(name)?;
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
                 OpCode::Return => {
-                    //if let Some(value) = self.pop() {
-                    //    println!("{}", value);
-                    //}
-                    //return Ok(());
+                    let result = self.pop().expect("empty stack");
+                    let frame = self.frames.pop().expect("no call frame");
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    self.push(result);
+                    if self.frames.len() == depth {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    // Bodies of the instructions that come in a short and a long form.
+
+    fn get_local(&mut self, slot: usize) {
+        let slot = self.frame().slots + slot;
+        self.push(self.stack[slot]);
+    }
+
+    fn set_local(&mut self, slot: usize) {
+        let slot = self.frame().slots + slot;
+        self.stack[slot] = self.peek(0).unwrap();
+    }
+
+    fn get_global_slot(&mut self, slot: usize) -> Result<(), InterpretError> {
+        match self.globals.get(slot) {
+            Some(v) => {
+                self.push(v);
+                Ok(())
+            }
+            None => Err(self.undefined_variable(slot)),
+        }
+    }
+
+    fn define_global_slot(&mut self, slot: usize) {
+        let v = self.peek(0).unwrap();
+        self.globals.define(slot, v);
+        self.pop();
+    }
+
+    fn set_global_slot(&mut self, slot: usize) -> Result<(), InterpretError> {
+        let v = self.peek(0).unwrap();
+        if !self.globals.set(slot, v) {
+            return Err(self.undefined_variable(slot));
+        }
+        Ok(())
+    }
+
+    fn get_property(&mut self, name_idx: usize) -> Result<(), InterpretError> {
+        let instance = match self.peek(0).unwrap() {
+            Value::Obj(obj) if self.heap.get(obj).as_instance().is_some() => obj,
+            _ => {
+                return Err(self.runtime_error("Only instances have properties."));
+            }
+        };
+        let name = self.read_string(name_idx);
+
+        let instance = self.heap.get(instance).as_instance().unwrap();
+        match instance.fields.get(&name).copied() {
+            Some(value) => {
+                self.pop(); // Instance.
+                self.push(value);
+                Ok(())
+            }
+            None => {
+                let class = instance.class;
+                self.bind_method(class, name)
+            }
+        }
+    }
+
+    fn set_property(&mut self, name_idx: usize) -> Result<(), InterpretError> {
+        let instance = match self.peek(1).unwrap() {
+            Value::Obj(obj) if self.heap.get(obj).as_instance().is_some() => obj,
+            _ => {
+                return Err(self.runtime_error("Only instances have fields."));
+            }
+        };
+        let name = self.read_string(name_idx);
+
+        let value = self.pop().unwrap();
+        self.heap
+            .get_mut(instance)
+            .as_instance_mut()
+            .unwrap()
+            .fields
+            .insert(name, value);
+        self.pop(); // Instance.
+        self.push(value);
+        Ok(())
+    }
+
+    fn get_super(&mut self, name_idx: usize) -> Result<(), InterpretError> {
+        let name = self.read_string(name_idx);
+        let superclass = self.pop_superclass()?;
+        self.bind_method(superclass, name)
+    }
+
+    fn super_invoke(&mut self, name_idx: usize, arg_count: usize) -> Result<(), InterpretError> {
+        let name = self.read_string(name_idx);
+        let superclass = self.pop_superclass()?;
+        self.invoke_from_class(superclass, name, arg_count)
+    }
+
+    // Hand-written bytecode may leave anything where the compiler would have
+    // loaded `super`.
+    fn pop_superclass(&mut self) -> Result<ObjRef, InterpretError> {
+        match self.pop().unwrap() {
+            Value::Obj(obj) if self.heap.get(obj).as_class().is_some() => Ok(obj),
+            _ => Err(self.runtime_error("Superclass must be a class.")),
+        }
+    }
+
+    fn new_closure(&mut self, idx: usize) -> Result<(), InterpretError> {
+        let function = match self.read_const(idx) {
+            Value::Obj(obj) if self.heap.get(obj).as_function().is_some() => obj,
+            _ => return Err(self.runtime_error("Can only close over functions.")),
+        };
+        let mut closure = Closure::new(function);
+        let upvalues = self
+            .heap
+            .get(function)
+            .as_function()
+            .unwrap()
+            .upvalues
+            .clone();
+        for upvalue in upvalues {
+            let captured = if upvalue.is_local {
+                let slot = self.frame().slots + upvalue.index as usize;
+                self.capture_upvalue(slot)
+            } else {
+                self.closure().upvalues[upvalue.index as usize]
+            };
+            closure.upvalues.push(captured);
+        }
+        let closure = self.alloc(Object::Closure(closure));
+        self.push(Value::Obj(closure));
+        Ok(())
+    }
+
+    fn new_class(&mut self, name_idx: usize) {
+        let name = self.read_string(name_idx);
+        let name = self.heap.get(name).as_str().to_owned();
+        let class = self.alloc(Object::Class(Class::new(name)));
+        self.push(Value::Obj(class));
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretError> {
+        if let Value::Obj(obj) = callee {
+            let slot = self.stack.len() - arg_count - 1;
+            match self.heap.get(obj) {
+                Object::BoundMethod(bound) => {
+                    let method = bound.method;
+                    self.stack[slot] = bound.receiver;
+                    return self.call_closure(method, arg_count);
+                }
+                Object::Class(class) => {
+                    let initializer = class.methods.get(&self.init_string).copied();
+                    let instance = self.alloc(Object::Instance(Instance::new(obj)));
+                    self.stack[slot] = Value::Obj(instance);
+                    if let Some(initializer) = initializer {
+                        return self.call_closure(initializer, arg_count);
+                    } else if arg_count != 0 {
+                        return Err(self.runtime_error(format!(
+                            "Expected 0 arguments but got {}.",
+                            arg_count
+                        )));
+                    }
                     return Ok(());
                 }
+                Object::Closure(_) => return self.call_closure(obj, arg_count),
+                Object::Native(native) => {
+                    if arg_count != native.arity {
+                        return Err(self.runtime_error(format!(
+                            "Expected {} arguments but got {}.",
+                            native.arity, arg_count
+                        )));
+                    }
+
+                    let function = Rc::clone(&native.function);
+                    let args = self.stack[slot + 1..].to_vec();
+                    match function(self, &args) {
+                        Ok(result) => {
+                            self.stack.truncate(slot);
+                            self.push(result);
+                            return Ok(());
+                        }
+                        Err(err) => {
+                            return Err(self.runtime_error(err.message));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Err(self.runtime_error("Can only call functions and classes."))
+    }
+
+    fn invoke(&mut self, name: ObjRef, arg_count: usize) -> Result<(), InterpretError> {
+        let receiver = match self.peek(arg_count).unwrap() {
+            Value::Obj(obj) if self.heap.get(obj).as_instance().is_some() => obj,
+            _ => {
+                return Err(self.runtime_error("Only instances have methods."));
             }
-            self.ip += 1
+        };
+        let instance = self.heap.get(receiver).as_instance().unwrap();
+
+        if let Some(value) = instance.fields.get(&name).copied() {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        arg_count: usize,
+    ) -> Result<(), InterpretError> {
+        let class = self.heap.get(class).as_class().unwrap();
+        match class.methods.get(&name).copied() {
+            Some(method) => self.call_closure(method, arg_count),
+            None => {
+                let name = self.heap.get(name).as_str();
+                Err(self.runtime_error(format!("Undefined property '{}'.", name)))
+            }
+        }
+    }
+
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), InterpretError> {
+        let class = self.heap.get(class).as_class().unwrap();
+        let method = match class.methods.get(&name).copied() {
+            Some(method) => method,
+            None => {
+                let name = self.heap.get(name).as_str();
+                return Err(self.runtime_error(format!("Undefined property '{}'.", name)));
+            }
+        };
+
+        let receiver = self.pop().unwrap();
+        let bound = self.alloc(Object::BoundMethod(BoundMethod::new(receiver, method)));
+        self.push(Value::Obj(bound));
+        Ok(())
+    }
+
+    fn define_method(&mut self, name: ObjRef) -> Result<(), InterpretError> {
+        let method = match self.pop().unwrap() {
+            Value::Obj(obj) if self.heap.get(obj).as_closure().is_some() => obj,
+            _ => return Err(self.runtime_error("Method must be a function.")),
+        };
+        match self.peek(0).unwrap() {
+            Value::Obj(obj) if self.heap.get(obj).as_class().is_some() => {
+                let class = self.heap.get_mut(obj).as_class_mut().unwrap();
+                class.methods.insert(name, method);
+                Ok(())
+            }
+            _ => Err(self.runtime_error("Only classes have methods.")),
+        }
+    }
+
+    fn call_closure(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), InterpretError> {
+        let function = self
+            .heap
+            .get(closure)
+            .as_closure()
+            .expect("not a closure")
+            .function;
+        let arity = self.heap.get(function).as_function().unwrap().arity;
+        if arg_count != arity {
+            return Err(self.runtime_error(format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            )));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let heap = &self.heap;
+        let found =
+            self.open_upvalues
+                .binary_search_by_key(&slot, |upvalue| match heap.get(*upvalue) {
+                    Object::Upvalue(Upvalue::Open(slot)) => *slot,
+                    _ => unreachable!("closed upvalue in open list"),
+                });
+        match found {
+            Ok(i) => self.open_upvalues[i],
+            Err(i) => {
+                let upvalue = self.alloc(Object::Upvalue(Upvalue::Open(slot)));
+                self.open_upvalues.insert(i, upvalue);
+                upvalue
+            }
+        }
+    }
+
+    // Move every open upvalue pointing at or above `last` off the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let upvalue = self.heap.get_mut(upvalue).as_upvalue_mut().unwrap();
+            let slot = match *upvalue {
+                Upvalue::Open(slot) if slot >= last => slot,
+                _ => break,
+            };
+            *upvalue = Upvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
         }
     }
 
@@ -195,29 +983,115 @@ impl VM {
         let b = match self.pop().expect("empty stack") {
             Value::Number(number) => number,
             _ => {
-                self.runtime_error("Operands must be numbers.");
-                return Err(InterpretError::RuntimeError);
+                return Err(self.runtime_error("Operands must be numbers."));
             }
         };
         let a = match self.pop().expect("empty stack") {
             Value::Number(number) => number,
             _ => {
-                self.runtime_error("Operands must be numbers.");
-                return Err(InterpretError::RuntimeError);
+                return Err(self.runtime_error("Operands must be numbers."));
             }
         };
         self.push(f(a, b));
         Ok(())
     }
 
+    fn read_op(&mut self) -> OpCode {
+        let frame = self.frames.last_mut().expect("no call frame");
+        let function = self.heap.get(frame.function).as_function().unwrap();
+        let (op, next) = function
+            .chunk
+            .read_instruction(frame.ip)
+            .expect("invalid instruction");
+        frame.ip = next;
+        op
+    }
+
     fn read_const(&self, idx: usize) -> Value {
-        self.chunk.constants.values[idx].clone()
+        self.chunk().constants.values[idx]
+    }
+
+    fn read_string(&self, idx: usize) -> ObjRef {
+        self.read_const(idx)
+            .as_obj()
+            .expect("constant is not a string")
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no call frame")
+    }
+
+    fn closure(&self) -> &Closure {
+        self.heap.get(self.frame().closure).as_closure().unwrap()
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self
+            .heap
+            .get(self.frame().function)
+            .as_function()
+            .unwrap()
+            .chunk
+    }
+
+    // Memory
+
+    pub(crate) fn alloc(&mut self, object: Object) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage(&object);
+        }
+        self.heap.alloc(object)
+    }
+
+    pub(crate) fn add_compiler_root(&mut self, obj: ObjRef) {
+        self.compiler_roots.push(obj);
+    }
+
+    pub(crate) fn clear_compiler_roots(&mut self) {
+        self.compiler_roots.clear();
+    }
+
+    // `pending` is the object about to be allocated; whatever it refers to
+    // must survive even though nothing else may point to it yet.
+    fn collect_garbage(&mut self, pending: &Object) {
+        let mut roots = Vec::new();
+        pending.trace(&mut roots);
+        roots.extend(&self.compiler_roots);
+        let host_roots = self.host_roots.get_mut();
+        host_roots.retain(|(handle, _)| handle.strong_count() > 0);
+        roots.extend(host_roots.iter().map(|&(_, obj)| obj));
+        roots.extend(self.globals.names());
+        roots.push(self.init_string);
+        roots.extend(&self.open_upvalues);
+        for frame in &self.frames {
+            roots.push(frame.closure);
+        }
+
+        for value in self.stack.iter().copied().chain(self.globals.values()) {
+            self.heap.mark_value(value);
+        }
+        for root in roots {
+            self.heap.mark_object(root);
+        }
+        self.heap.trace_references();
+
+        let heap = &self.heap;
+        self.strings.retain(|_, string| heap.is_marked(*string));
+        self.heap.sweep();
     }
 
     // Stack
 
     fn reset_stack(&mut self) {
+        // Closures that outlive the error must keep their captured values.
+        self.close_upvalues(0);
         self.stack.clear();
+        self.frames.clear();
+        self.suspended = None;
     }
 
     fn push(&mut self, value: Value) {
@@ -232,7 +1106,7 @@ impl VM {
     fn peek(&self, distance: usize) -> Option<Value> {
         let offset = 1 + distance;
         if offset <= self.stack.len() {
-            Some(self.stack[self.stack.len() - offset].clone())
+            Some(self.stack[self.stack.len() - offset])
         } else {
             None
         }
@@ -240,11 +1114,48 @@ impl VM {
 
     // Error
 
-    fn runtime_error(&mut self, message: &str) {
-        eprintln!("{}", message);
-        let line = self.chunk.lines[self.ip];
-        eprintln!("[line {}] in script", line);
-        self.reset_stack();
+    fn undefined_variable(&mut self, slot: usize) -> InterpretError {
+        let name = self.heap.get(self.globals.name(slot)).as_str();
+        let message = format!("Undefined variable '{}'.", name);
+        self.runtime_error(message)
+    }
+
+    fn runtime_error(&mut self, message: impl Into<String>) -> InterpretError {
+        InterpretError::RuntimeError(self.capture_error(message))
+    }
+
+    // Captures the call stack for `message` and unwinds the VM.
+    fn capture_error(&mut self, message: impl Into<String>) -> RuntimeError {
+        let (frame_base, stack_base) = self.call_base.unwrap_or((0, 0));
+        let trace: Vec<StackFrame> = self.frames[frame_base..]
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self.heap.get(frame.function).as_function().unwrap();
+                let at = function.chunk.position_at(frame.ip.saturating_sub(1));
+                StackFrame {
+                    function: function.name.clone(),
+                    line: at.line,
+                    column: at.column,
+                }
+            })
+            .collect();
+        let (line, column) = trace.first().map_or((0, 0), |f| (f.line, f.column));
+
+        if self.call_base.is_some() {
+            // Whatever is running beneath the call is left as it was.
+            self.close_upvalues(stack_base);
+            self.frames.truncate(frame_base);
+            self.stack.truncate(stack_base);
+        } else {
+            self.reset_stack();
+        }
+        RuntimeError {
+            message: message.into(),
+            line,
+            column,
+            trace,
+        }
     }
 }
 
@@ -252,13 +1163,910 @@ fn is_falsey(value: Value) -> bool {
     matches!(value, Value::Nil | Value::Boolean(false))
 }
 
+// Strings are interned, so identity is equality for every object type.
 fn values_equal(a: Value, b: Value) -> bool {
     match a {
         Value::Boolean(val_a) => matches!(b, Value::Boolean(val_b) if val_a == val_b),
         Value::Number(val_a) => matches!(b, Value::Number(val_b) if val_a == val_b),
         Value::Nil => matches!(b, Value::Nil),
-        Value::Obj(obj_a) => {
-            matches!(b, Value::Obj(obj_b) if Object::values_equal(obj_a.as_ref(), obj_b.as_ref()))
+        Value::Obj(obj_a) => matches!(b, Value::Obj(obj_b) if obj_a == obj_b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Position;
+
+    fn vm() -> VM {
+        VM::with_options(VmOptions::new().stress_gc(true))
+    }
+
+    // A `Write` the test can read back after handing a clone to the VM.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn global(vm: &VM, name: &str) -> Option<Value> {
+        vm.get_global(name).map(|global| global.value())
+    }
+
+    fn global_string(vm: &VM, name: &str) -> Option<String> {
+        global(vm, name).and_then(|v| v.string(&vm.heap))
+    }
+
+    fn global_number(vm: &VM, name: &str) -> Option<f64> {
+        match global(vm, name) {
+            Some(Value::Number(n)) => Some(n),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn while_loop() {
+        let mut vm = vm();
+        vm.interpret("var i = 0; var sum = 0; while (i < 5) { sum = sum + i; i = i + 1; }")
+            .unwrap();
+
+        assert_eq!(global_number(&vm, "i"), Some(5.0));
+        assert_eq!(global_number(&vm, "sum"), Some(10.0));
+    }
+
+    #[test]
+    fn while_loop_false_condition() {
+        let mut vm = vm();
+        vm.interpret("var i = 0; while (false) i = i + 1;").unwrap();
+
+        assert_eq!(global_number(&vm, "i"), Some(0.0));
+    }
+
+    #[test]
+    fn for_loop() {
+        let mut vm = vm();
+        vm.interpret("var sum = 0; for (var i = 0; i < 5; i = i + 1) sum = sum + i;")
+            .unwrap();
+
+        assert_eq!(global_number(&vm, "sum"), Some(10.0));
+        assert!(global(&vm, "i").is_none());
+    }
+
+    #[test]
+    fn for_loop_without_clauses() {
+        let mut vm = vm();
+        vm.interpret("var i = 0; for (; i < 3;) i = i + 1;")
+            .unwrap();
+
+        assert_eq!(global_number(&vm, "i"), Some(3.0));
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let mut vm = vm();
+        vm.interpret(
+            "var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                var j = i;
+                if (j == 2) continue;
+                if (j == 5) break;
+                sum = sum + j;
+            }
+            var n = 0;
+            while (true) { var k = n; n = n + 1; if (k == 3) break; }",
+        )
+        .unwrap();
+
+        assert_eq!(global_number(&vm, "sum"), Some(8.0));
+        assert_eq!(global_number(&vm, "n"), Some(4.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn call_function() {
+        let mut vm = vm();
+        vm.interpret(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+            var result = fib(10);
+            fun noop() {}
+            var nothing = noop();",
+        )
+        .unwrap();
+
+        assert_eq!(global_number(&vm, "result"), Some(55.0));
+        assert!(matches!(global(&vm, "nothing"), Some(Value::Nil)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn closures_capture_variables() {
+        let mut vm = vm();
+        vm.interpret(
+            "fun make_counter() {
+                var count = 0;
+                fun counter() { count = count + 1; return count; }
+                return counter;
+            }
+            var c1 = make_counter();
+            var c2 = make_counter();
+            c1(); c1();
+            var a = c1();
+            var b = c2();
+
+            var get; var set;
+            {
+                var shared = 1;
+                fun g() { return shared; }
+                fun s(v) { shared = v; }
+                get = g; set = s;
+            }
+            set(42);
+            var shared = get();
+
+            var inner;
+            fun outer() {
+                var x = \"outside\";
+                fun middle() { fun innermost() { return x; } return innermost; }
+                inner = middle();
+            }
+            outer();
+            var deep = inner();",
+        )
+        .unwrap();
+
+        assert_eq!(global_number(&vm, "a"), Some(3.0));
+        assert_eq!(global_number(&vm, "b"), Some(1.0));
+        assert_eq!(global_number(&vm, "shared"), Some(42.0));
+        assert_eq!(global_string(&vm, "deep").as_deref(), Some("outside"));
+        assert!(vm.stack.is_empty());
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn runtime_error_closes_captured_locals() {
+        let mut vm = vm();
+        let result = vm.interpret(
+            "var f;
+            fun outer() { var x = 1; fun g() { return x; } f = g; return nil + 1; }
+            outer();",
+        );
+        assert!(matches!(result, Err(InterpretError::RuntimeError(_))));
+        assert!(vm.open_upvalues.is_empty());
+
+        vm.interpret("var x = f();").unwrap();
+        assert_eq!(global_number(&vm, "x"), Some(1.0));
+    }
+
+    #[test]
+    fn break_closes_captured_locals() {
+        let mut vm = vm();
+        vm.interpret(
+            "var f;
+            while (true) { var x = 7; fun g() { return x; } f = g; break; }
+            var result = f();",
+        )
+        .unwrap();
+
+        assert_eq!(global_number(&vm, "result"), Some(7.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn instance_fields() {
+        let mut vm = vm();
+        vm.interpret(
+            "class Pair {}
+            var pair = Pair();
+            pair.first = 1;
+            pair.second = 2;
+            var sum = pair.first + pair.second;
+            pair.first = pair.second = 5;
+            var first = pair.first;",
+        )
+        .unwrap();
+
+        assert_eq!(global_number(&vm, "sum"), Some(3.0));
+        assert_eq!(global_number(&vm, "first"), Some(5.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn methods_and_initializers() {
+        let mut vm = vm();
+        vm.interpret(
+            "class Counter {
+                init(start) { this.count = start; }
+                add(n) { this.count = this.count + n; return this; }
+                get() { return this.count; }
+            }
+            var counter = Counter(10);
+            var chained = counter.add(1).add(2).get();
+            var m = counter.get;
+            var bound = m();
+            var reinit = counter.init(0).get();
+
+            class Callback { init() { this.fn = nil; } }
+            fun seven() { return 7; }
+            var cb = Callback();
+            cb.fn = seven;
+            var field_call = cb.fn();",
+        )
+        .unwrap();
+
+        assert_eq!(global_number(&vm, "chained"), Some(13.0));
+        assert_eq!(global_number(&vm, "bound"), Some(13.0));
+        assert_eq!(global_number(&vm, "reinit"), Some(0.0));
+        assert_eq!(global_number(&vm, "field_call"), Some(7.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn inheritance_and_super() {
+        let mut vm = vm();
+        vm.interpret(
+            "class A {
+                init(x) { this.x = x; }
+                name() { return \"A\"; }
+                value() { return this.x; }
+            }
+            class B < A {
+                init(x) { super.init(x * 2); }
+                name() { return \"B\"; }
+                parent_name() { var m = super.name; return m(); }
+                value() { return super.value() + 1; }
+            }
+            var b = B(5);
+            var value = b.value();
+            var name = b.name();
+            var parent_name = b.parent_name();",
+        )
+        .unwrap();
+
+        assert_eq!(global_number(&vm, "value"), Some(11.0));
+        assert_eq!(global_string(&vm, "name").as_deref(), Some("B"));
+        assert_eq!(global_string(&vm, "parent_name").as_deref(), Some("A"));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn inherit_from_non_class() {
+        let mut vm = vm();
+        let result = vm.interpret("var NotClass = 1; class Sub < NotClass {}");
+
+        assert!(matches!(result, Err(InterpretError::RuntimeError(_))));
+    }
+
+    #[test]
+    fn initializer_arity() {
+        let cases = [
+            "class Foo { init(a) {} } Foo();",
+            "class Bar {} Bar(1);",
+            "class Baz {} Baz().missing();",
+            "var x = 1; x.method();",
+        ];
+        for source in cases {
+            let mut vm = vm();
+            let result = vm.interpret(source);
+
+            assert!(
+                matches!(result, Err(InterpretError::RuntimeError(_))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn property_errors() {
+        let cases = [
+            "class Foo {} var foo = Foo(); foo.missing;",
+            "var x = 1; x.field;",
+            "var x = true; x.field = 1;",
+        ];
+        for source in cases {
+            let mut vm = vm();
+            let result = vm.interpret(source);
+
+            assert!(
+                matches!(result, Err(InterpretError::RuntimeError(_))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn native_functions() {
+        fn add(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+            match (&args[0], &args[1]) {
+                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+                _ => Err(RuntimeError::new("add() expects numbers.")),
+            }
+        }
+
+        let mut vm = vm();
+        vm.define_native("add", 2, add);
+        vm.interpret(
+            "var sum = add(1, 2);
+            var length = len(\"hello\");
+            var kind = type_of(len);
+            var text = to_string(1.5) + to_string(nil);
+            var elapsed = clock() >= 0;",
+        )
+        .unwrap();
+
+        assert_eq!(global_number(&vm, "sum"), Some(3.0));
+        assert_eq!(global_number(&vm, "length"), Some(5.0));
+        assert_eq!(global_string(&vm, "kind").as_deref(), Some("function"));
+        assert_eq!(global_string(&vm, "text").as_deref(), Some("1.5nil"));
+        assert!(matches!(global(&vm, "elapsed"), Some(Value::Boolean(true))));
+        assert!(vm.stack.is_empty());
+
+        for source in ["add(1);", "add(1, true);", "len(1);"] {
+            let result = vm.interpret(source);
+            assert!(
+                matches!(result, Err(InterpretError::RuntimeError(_))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn collects_unreachable_cycles() {
+        let mut vm = vm();
+        vm.interpret(
+            "class Node {}
+            fun cycle() {
+                var a = Node(); var b = Node();
+                a.next = b; b.next = a;
+                a.name = \"a\" + \"b\";
+            }
+            for (var i = 0; i < 100; i = i + 1) cycle();",
+        )
+        .unwrap();
+        let live = vm.heap.len();
+        vm.interpret("for (var i = 0; i < 1000; i = i + 1) cycle();")
+            .unwrap();
+
+        assert!(vm.heap.len() <= live + 10, "{} > {}", vm.heap.len(), live);
+    }
+
+    #[test]
+    fn strings_are_interned() {
+        let mut vm = vm();
+        vm.interpret(
+            "var a = \"hello\";
+            var b = \"hel\" + \"lo\";
+            var same = a == b;
+            var different = a == \"world\";",
+        )
+        .unwrap();
+
+        assert!(matches!(global(&vm, "same"), Some(Value::Boolean(true))));
+        assert!(matches!(
+            global(&vm, "different"),
+            Some(Value::Boolean(false))
+        ));
+        match (global(&vm, "a"), global(&vm, "b")) {
+            (Some(Value::Obj(a)), Some(Value::Obj(b))) => assert_eq!(a, b),
+            other => panic!("expected strings, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unreachable_strings_leave_the_intern_table() {
+        let mut vm = vm();
+        vm.interpret("var s = \"tmp\" + \"orary\"; s = nil;")
+            .unwrap();
+        vm.interpret("var t = 1;").unwrap();
+
+        assert!(!vm.strings.contains_key("temporary"));
+    }
+
+    #[test]
+    fn globals_resolve_to_slots() {
+        let mut vm = vm();
+        vm.interpret(
+            "fun read() { return later; }
+            var later = 1;
+            var first = read();",
+        )
+        .unwrap();
+        vm.interpret("var later = 2; var second = read();").unwrap();
+
+        assert_eq!(global_number(&vm, "first"), Some(1.0));
+        assert_eq!(global_number(&vm, "second"), Some(2.0));
+
+        for source in ["missing;", "missing = 1;", "fun f() { return nope; } f();"] {
+            let result = vm.interpret(source);
+            assert!(
+                matches!(result, Err(InterpretError::RuntimeError(_))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn call_with_wrong_arity() {
+        let mut vm = vm();
+        let result = vm.interpret("fun f(a, b) { return a + b; } f(1);");
+
+        assert!(matches!(result, Err(InterpretError::RuntimeError(_))));
+    }
+
+    #[test]
+    fn call_non_function() {
+        let mut vm = vm();
+        let result = vm.interpret("var x = 1; x();");
+
+        assert!(matches!(result, Err(InterpretError::RuntimeError(_))));
+    }
+
+    #[test]
+    fn runtime_error_carries_stack_trace() {
+        let mut vm = vm();
+        let result = vm.interpret(
+            "fun inner(x) {
+              return x + nil;
+            }
+            class A { outer() { return inner(1); } }
+            A().outer();",
+        );
+        let err = match result {
+            Err(InterpretError::RuntimeError(err)) => err,
+            other => panic!("expected a runtime error, got {:?}", other),
+        };
+
+        assert_eq!(err.message, "Operands must be numbers.");
+        assert_eq!((err.line, err.column), (2, 24));
+        let frames: Vec<_> = err
+            .trace
+            .iter()
+            .map(|f| (f.function.as_deref(), f.line))
+            .collect();
+        assert_eq!(frames, [(Some("inner"), 2), (Some("outer"), 4), (None, 5)]);
+        assert_eq!(
+            err.to_string(),
+            "Operands must be numbers.\n\
+             [line 2:24] in inner()\n\
+             [line 4:47] in outer()\n\
+             [line 5:23] in script"
+        );
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+
+        // Errors raised by natives get the caller's position and trace too.
+        match vm.interpret("fun f() { len(1); }\nf();") {
+            Err(InterpretError::RuntimeError(err)) => {
+                assert_eq!(err.message, "Argument to 'len' must be a string.");
+                assert_eq!((err.line, err.column), (1, 16));
+                assert_eq!(err.trace.len(), 2);
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
         }
     }
+    #[test]
+    fn thousands_of_constants() {
+        let mut source = String::from("var sum = 0;");
+        for i in 0..3000 {
+            source.push_str(&format!("sum = sum + {};", i));
+        }
+
+        let mut vm = vm();
+        let function = Compiler::new(&mut vm, &source).compile().unwrap();
+        assert!(function
+            .chunk
+            .instructions()
+            .any(|(_, op)| matches!(op, OpCode::ConstantLong(_))));
+
+        vm.interpret(&source).unwrap();
+        assert_eq!(global_number(&vm, "sum"), Some(4498500.0));
+    }
+
+    #[test]
+    fn thousands_of_globals() {
+        let mut source = String::new();
+        for i in 0..1000 {
+            source.push_str(&format!("var g{} = {};", i, i));
+        }
+        source.push_str("g999 = g999 + g300; var total = g0 + g999;");
+
+        let mut vm = vm();
+        vm.interpret(&source).unwrap();
+
+        assert_eq!(global_number(&vm, "g999"), Some(1299.0));
+        assert_eq!(global_number(&vm, "total"), Some(1299.0));
+    }
+
+    #[test]
+    fn hundreds_of_locals() {
+        let mut locals = String::new();
+        for i in 0..300 {
+            locals.push_str(&format!("var l{} = {};", i, i));
+        }
+        let source = format!(
+            "var sum; var read;
+            {{ {} l299 = l299 + l1; sum = l0 + l299; }}
+            fun f() {{ {} fun g() {{ return l280; }} return g; }}
+            read = f();
+            var captured = read();",
+            locals, locals
+        );
+
+        let mut vm = vm();
+        vm.interpret(&source).unwrap();
+
+        assert_eq!(global_number(&vm, "sum"), Some(300.0));
+        assert_eq!(global_number(&vm, "captured"), Some(280.0));
+    }
+
+    #[test]
+    fn classes_after_many_constants() {
+        let mut source = String::from("var sum = 0;");
+        for i in 0..300 {
+            source.push_str(&format!("sum = sum + {};", i));
+        }
+        source.push_str(
+            "class A { init(x) { this.x = x; } get() { return this.x; } }
+            class B < A { get() { return super.get() + 1; } base() { return super.get; } }
+            var b = B(41);
+            b.y = b.get();
+            var got = b.y;
+            var bound = b.base()();",
+        );
+
+        let mut vm = vm();
+        vm.interpret(&source).unwrap();
+
+        assert_eq!(global_number(&vm, "got"), Some(42.0));
+        assert_eq!(global_number(&vm, "bound"), Some(41.0));
+    }
+
+    #[test]
+    fn instructions_record_token_positions() {
+        let mut vm = vm();
+        let source = "var a = 1;\nvar b = a +\n  -nil;\nb.field;";
+        let function = Compiler::new(&mut vm, source).compile().unwrap();
+        let chunk = &function.chunk;
+        let position = |wanted: fn(&OpCode) -> bool| {
+            let (offset, _) = chunk.instructions().find(|(_, op)| wanted(op)).unwrap();
+            let at = chunk.position_at(offset);
+            (at.line, at.column)
+        };
+
+        assert_eq!(position(|op| matches!(op, OpCode::Add)), (2, 11));
+        assert_eq!(position(|op| matches!(op, OpCode::Negate)), (3, 3));
+        assert_eq!(position(|op| matches!(op, OpCode::GetProperty(_))), (4, 3));
+    }
+
+    #[test]
+    fn run_loaded_chunk() {
+        let mut compiling = vm();
+        compiling.interpret("var unrelated = 0;").unwrap();
+        let function = Compiler::new(
+            &mut compiling,
+            "class A { init(n) { this.n = n; } }
+            fun counter() { var i = 0; fun inc() { i = i + 1; return i; } return inc; }
+            var c = counter();
+            c();
+            var total = A(40).n + c();
+            var s = \"lo\" + \"x\";",
+        )
+        .compile()
+        .unwrap();
+        let mut bytes = Vec::new();
+        function.chunk.write_to(&mut bytes, &compiling).unwrap();
+
+        // Fewer globals here, so every global instruction moves to a new slot.
+        let mut vm = vm();
+        let script = Chunk::read_from(bytes.as_slice(), &mut vm).unwrap();
+        vm.call(script.value(), &[]).unwrap();
+
+        assert_eq!(global_number(&vm, "total"), Some(42.0));
+        assert_eq!(global_string(&vm, "s").as_deref(), Some("lox"));
+        assert!(global(&vm, "unrelated").is_none());
+    }
+
+    #[test]
+    fn compile_once_run_many_times() {
+        let mut vm = vm();
+        vm.set_global("count", Value::Number(10.0));
+        let script = vm.compile("count = count + 1;").unwrap();
+
+        for _ in 0..3 {
+            let result = vm.call(script.value(), &[]).unwrap();
+            assert!(matches!(result.value(), Value::Nil));
+        }
+        assert_eq!(global_number(&vm, "count"), Some(13.0));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+
+        // The script survives collections while the host holds on to it.
+        vm.interpret("var garbage = \"a\" + \"b\";").unwrap();
+        vm.call(script.value(), &[]).unwrap();
+        assert_eq!(global_number(&vm, "count"), Some(14.0));
+    }
+
+    #[test]
+    fn dropped_handles_are_collected() {
+        let mut vm = vm();
+        vm.set_global("count", Value::Number(0.0));
+        let before = vm.heap.len();
+        let script = vm.compile("count = count + 1;").unwrap();
+        let copy = script.clone();
+        vm.call(script.value(), &[]).unwrap();
+        drop(script);
+        vm.new_string("collect");
+        assert_eq!(vm.heap.len(), before + 3);
+
+        drop(copy);
+        vm.new_string("again");
+        assert_eq!(vm.heap.len(), before + 1);
+    }
+
+    #[test]
+    fn host_values_survive_allocations() {
+        let mut vm = vm();
+        vm.interpret("fun cat(a, b) { return a + b; }").unwrap();
+        let cat = vm.get_global("cat").unwrap();
+
+        let a = vm.new_string("left-");
+        let b = vm.new_string("right");
+        vm.interpret("var garbage = \"x\" + \"y\";").unwrap();
+        assert_eq!(a.value().string(&vm.heap).as_deref(), Some("left-"));
+        assert_eq!(b.value().string(&vm.heap).as_deref(), Some("right"));
+
+        let joined = vm.call(cat.value(), &[a.value(), b.value()]).unwrap();
+        let again = vm.call(cat.value(), &[b.value(), a.value()]).unwrap();
+        let joined = joined.value().string(&vm.heap);
+        assert_eq!(joined.as_deref(), Some("left-right"));
+        assert_eq!(
+            again.value().string(&vm.heap).as_deref(),
+            Some("rightleft-")
+        );
+    }
+
+    #[test]
+    fn call_lox_from_rust() {
+        let mut vm = vm();
+        vm.interpret(
+            "fun add(a, b) { return a + b; }
+            fun greet(name) { return \"hi \" + name; }
+            class Point { init(x) { this.x = x; } }",
+        )
+        .unwrap();
+
+        let add = global(&vm, "add").unwrap();
+        let sum = vm.call(add, &[Value::Number(1.0), Value::Number(2.0)]);
+        assert!(matches!(sum.unwrap().value(), Value::Number(n) if n == 3.0));
+
+        let greet = global(&vm, "greet").unwrap();
+        let name = vm.new_string("lox");
+        let greeting = vm.call(greet, &[name.value()]).unwrap();
+        let greeting = greeting.value().string(&vm.heap);
+        assert_eq!(greeting.as_deref(), Some("hi lox"));
+
+        let len = global(&vm, "len").unwrap();
+        let length = vm.call(len, &[name.value()]).unwrap();
+        assert!(matches!(length.value(), Value::Number(n) if n == 3.0));
+
+        let point = global(&vm, "Point").unwrap();
+        let point = vm.call(point, &[Value::Number(4.0)]).unwrap();
+        vm.set_global("p", point.value());
+        vm.interpret("var x = p.x;").unwrap();
+        assert_eq!(global_number(&vm, "x"), Some(4.0));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+    }
+
+    #[test]
+    fn call_errors_from_rust() {
+        let mut vm = vm();
+        vm.interpret("fun fail(x) { return -x; }").unwrap();
+        assert!(vm.get_global("missing").is_none());
+
+        let err = vm.call(Value::Number(1.0), &[]).unwrap_err();
+        assert_eq!(err.message, "Can only call functions and classes.");
+
+        let fail = global(&vm, "fail").unwrap();
+        let err = vm.call(fail, &[]).unwrap_err();
+        assert_eq!(err.message, "Expected 1 arguments but got 0.");
+
+        let err = vm.call(fail, &[Value::Nil]).unwrap_err();
+        assert_eq!(err.message, "Operand must be a number.");
+        assert_eq!(err.trace.len(), 1);
+        assert_eq!(err.trace[0].function.as_deref(), Some("fail"));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+    }
+
+    #[test]
+    fn output_goes_to_configured_sinks() {
+        let (output, errors, trace) = Default::default();
+        let mut vm = vm();
+        vm.set_output(Box::new(SharedBuffer::clone(&output)));
+        vm.set_error_output(Box::new(SharedBuffer::clone(&errors)));
+        vm.set_trace_output(Box::new(SharedBuffer::clone(&trace)));
+
+        vm.interpret("print 1 + 2; print \"two\"; print nil;")
+            .unwrap();
+        assert_eq!(output.contents(), "3\ntwo\nnil\n");
+        assert_eq!(errors.contents(), "");
+
+        let _ = vm.interpret("print ;");
+        let _ = vm.interpret("fun f() { return -nil; }\nf();");
+        assert_eq!(
+            errors.contents(),
+            "[line 1:7] Error at ';': Expect expression.\n\
+             Operand must be a number.\n\
+             [line 1:18] in f()\n\
+             [line 2:3] in script\n"
+        );
+        assert_eq!(output.contents(), "3\ntwo\nnil\n");
+
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Nil, Position { line: 1, column: 1 });
+        let _ = vm.interpret_chunk(chunk.clone());
+        assert!(errors.contents().ends_with(
+            "Invalid chunk: <script> at offset 1: execution can run past the end of the chunk\n"
+        ));
+
+        chunk.write_chunk(OpCode::Return, Position { line: 2, column: 1 });
+        vm.trace_chunk(&chunk, "test");
+        assert!(trace
+            .contents()
+            .ends_with("=== test ===\n0000    1 Nil\n0001    2 Return\n"));
+    }
+
+    #[test]
+    fn debug_options_write_to_trace_output() {
+        let run = |options: VmOptions| {
+            let (output, trace) = <(SharedBuffer, SharedBuffer)>::default();
+            let mut vm = VM::with_options(options);
+            vm.set_output(Box::new(output.clone()));
+            vm.set_trace_output(Box::new(trace.clone()));
+            vm.interpret("print 1;").unwrap();
+            assert_eq!(output.contents(), "1\n");
+            trace.contents()
+        };
+
+        assert_eq!(run(VmOptions::new()), "");
+        assert_eq!(
+            run(VmOptions::new().dump_tokens(true)),
+            "   1:1   Print 'print'\n   1:7   Number '1'\n   1:8   Semicolon ';'\n   1:9   Eof ''\n"
+        );
+        assert_eq!(
+            run(VmOptions::new().print_code(true)),
+            "=== <script> ===\n0000    1 Constant 1\n0002    | Print\n0003    | Nil\n0004    | Return\n"
+        );
+        assert_eq!(
+            run(VmOptions::new().trace_execution(true)),
+            "0000    1 Constant 1\n0002    | Print\n0003    | Nil\n0004    | Return\n"
+        );
+    }
+
+    #[test]
+    fn fuel_limits_instructions() {
+        let output = SharedBuffer::default();
+        let mut vm = vm();
+        vm.set_output(Box::new(output.clone()));
+
+        // Constant, Print, Nil, Return.
+        vm.set_fuel(Some(4));
+        vm.interpret("print 1;").unwrap();
+        assert_eq!(vm.fuel(), Some(0));
+
+        vm.set_fuel(Some(3));
+        let result = vm.interpret("print 2;");
+        assert!(matches!(result, Err(InterpretError::OutOfFuel)));
+        assert_eq!(output.contents(), "1\n2\n");
+        vm.resume(1).unwrap();
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+
+        // Nothing is suspended now.
+        vm.resume(10).unwrap();
+        assert_eq!(vm.fuel(), Some(10));
+    }
+
+    #[test]
+    fn resume_runaway_script_in_slices() {
+        let mut vm = vm();
+        vm.set_fuel(Some(10));
+        let mut result = vm.interpret(
+            "var i = 0;
+            while (i < 100) i = i + 1;",
+        );
+        let mut slices = 1;
+        while let Err(InterpretError::OutOfFuel) = result {
+            assert_eq!(vm.fuel(), Some(0));
+            result = vm.resume(10);
+            slices += 1;
+        }
+        result.unwrap();
+        assert!(slices > 50);
+        assert_eq!(global_number(&vm, "i"), Some(100.0));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+
+        // A new script abandons one that never finishes.
+        vm.set_fuel(Some(1000));
+        let result = vm.interpret("while (true) {}");
+        assert!(matches!(result, Err(InterpretError::OutOfFuel)));
+        vm.set_fuel(None);
+        vm.interpret("var done = true;").unwrap();
+        assert!(matches!(global(&vm, "done"), Some(Value::Boolean(true))));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+
+        // Closures that escaped the abandoned script still work.
+        vm.set_fuel(Some(40));
+        let result = vm.interpret(
+            "var f;
+            fun outer() { var x = 1; fun g() { return x; } f = g; while (true) {} }
+            outer();",
+        );
+        assert!(matches!(result, Err(InterpretError::OutOfFuel)));
+        vm.set_fuel(None);
+        vm.interpret("var x = f();").unwrap();
+        assert_eq!(global_number(&vm, "x"), Some(1.0));
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn calls_run_out_of_fuel() {
+        let mut vm = vm();
+        vm.interpret("fun spin() { while (true) {} }").unwrap();
+        let spin = global(&vm, "spin").unwrap();
+
+        vm.set_fuel(Some(100));
+        let err = vm.call(spin, &[]).unwrap_err();
+        assert_eq!(err.message, "Out of fuel.");
+        assert_eq!(err.trace[0].function.as_deref(), Some("spin"));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+    }
+
+    #[test]
+    fn calls_leave_a_suspended_script_resumable() {
+        let mut vm = vm();
+        vm.interpret(
+            "fun fail() { return -nil; }
+            fun add(a, b) { return a + b; }
+            fun spin() { while (true) {} }",
+        )
+        .unwrap();
+        let (fail, add, spin) = (global(&vm, "fail"), global(&vm, "add"), global(&vm, "spin"));
+
+        vm.set_fuel(Some(10));
+        let result = vm.interpret(
+            "var i = 0;
+            fun count() { var n = 0; while (n < 100) { n = n + 1; i = n; } }
+            count();",
+        );
+        assert!(matches!(result, Err(InterpretError::OutOfFuel)));
+        let (frames, stack) = (vm.frames.len(), vm.stack.len());
+
+        vm.set_fuel(Some(1000));
+        let err = vm.call(fail.unwrap(), &[]).unwrap_err();
+        assert_eq!(err.message, "Operand must be a number.");
+        assert_eq!(err.trace.len(), 1);
+        let err = vm.call(spin.unwrap(), &[]).unwrap_err();
+        assert_eq!(err.message, "Out of fuel.");
+        vm.set_fuel(Some(1000));
+        let sum = vm.call(add.unwrap(), &[Value::Number(1.0), Value::Number(2.0)]);
+        assert!(matches!(sum.unwrap().value(), Value::Number(n) if n == 3.0));
+        assert_eq!((vm.frames.len(), vm.stack.len()), (frames, stack));
+
+        vm.resume(10_000).unwrap();
+        assert_eq!(global_number(&vm, "i"), Some(100.0));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+    }
 }